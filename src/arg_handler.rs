@@ -1,13 +1,171 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use pixelbomber::image_handler::{BinaryFormat, BulkFormat, ImageConfig};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// The host to pwn "host:port"
-    pub host: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The host to pwn "host:port", prefix with "udp://" to use UDP, "ws://" for WebSockets
+    /// or "tls://" for TLS
+    #[arg(required = true)]
+    pub host: Option<String>,
+
+    /// Image paths
+    pub image: Vec<String>,
+
+    #[command(flatten)]
+    pub encoding: Encoding,
+
+    /// Number of concurrent threads [default: CPUs]
+    #[arg(short, long, value_name = "THREADS")]
+    pub count: Option<u32>,
+
+    /// Disable automatic detection of supported features
+    #[arg(short, long)]
+    pub feature_detection: bool,
+
+    /// Bind address to use for communication
+    #[arg(long)]
+    pub bind_addr: Option<String>,
+
+    /// Decode the video or animation while playing it, instead of loading all frames first. At
+    /// most FRAMES encoded frames are kept in memory, and the input is decoded again to loop
+    #[arg(long, value_name = "FRAMES", conflicts_with_all = ["repair", "manager_images"],
+        value_parser = clap::value_parser!(u32).range(1..))]
+    pub stream: Option<u32>,
+
+    /// Keep encoded frames in DIR, later runs with the same input and settings load them from
+    /// there instead of encoding them again
+    #[arg(long, value_name = "DIR", conflicts_with = "stream")]
+    pub cache: Option<std::path::PathBuf>,
+
+    /// Run continuously (ignore EOF if using stdin)
+    #[arg(long)]
+    pub continuous: bool,
+
+    /// Test green screen for camera. This will print the first pixel of the camera
+    #[arg(long)]
+    pub test_green_screen: bool,
+
+    /// Green screen color, obtain by running `pixelbomber --test-green-screen /dev/video0`
+    #[arg(long)]
+    pub green_screen: Option<String>,
+
+    /// This pixelbomber is only a manager instead of a pixel fluter, listens at the specified port
+    #[arg(long, requires = "manager_secret")]
+    pub serve_manager: Option<u16>,
+
+    /// Listen to the manager at HOST for commands
+    #[arg(long, requires = "manager_secret")]
+    pub listen_manager: bool,
+
+    /// Send images to the clients of the manager instead of pixel commands, every client encodes
+    /// its share of the image itself. Saves a lot of bandwidth for videos and streams
+    #[arg(long, requires = "serve_manager")]
+    pub manager_images: bool,
+
+    /// Read back the canvas and only paint pixels that differ from the image, not with UDP
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Send pixel commands with MSG_ZEROCOPY (Linux only)
+    #[arg(long)]
+    pub zero_copy: bool,
+
+    /// Run the painters on a tokio runtime with the given number of worker threads
+    /// instead of one thread per connection
+    #[cfg(feature = "tokio")]
+    #[arg(long, value_name = "WORKERS")]
+    pub async_runtime: Option<usize>,
+
+    /// Print the throughput of the painters every SECS seconds, set RUST_LOG=info for every
+    /// painter on its own. A manager prints the combined statistics of its clients [default for
+    /// managers: 10]
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats: Option<u64>,
+
+    /// Accept commands to change the image, offset, size, fps and threads of the running
+    /// pixelbomber on 127.0.0.1:PORT, one command per line (e.g. `echo "offset 100 200" | nc 127.0.0.1 PORT`)
+    #[arg(long, value_name = "PORT", conflicts_with = "listen_manager")]
+    pub control: Option<u16>,
+
+    /// Detect how many connections the server accepts, by watching for painters that are refused
+    /// or dropped while others stay connected, and print it
+    #[arg(long, conflicts_with = "serve_manager")]
+    pub detect_limit: bool,
+
+    /// Reduce the number of painters to the detected connection limit
+    #[arg(long, requires = "detect_limit")]
+    pub adapt_threads: bool,
+
+    /// Shared secret between the manager and its clients, peers that don't know it are rejected
+    /// Required for --serve-manager and --listen-manager
+    #[arg(long, env = "PIXELBOMBER_MANAGER_SECRET", hide_env_values = true,
+        value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    pub manager_secret: Option<String>,
+
+    /// PEM certificate chain for the manager, enables TLS for --serve-manager
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "manager_key", value_name = "PEM")]
+    pub manager_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key belonging to --manager-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "manager_cert", value_name = "PEM")]
+    pub manager_key: Option<std::path::PathBuf>,
+
+    /// SHA-256 fingerprint of the manager certificate, enables TLS for --listen-manager
+    /// The manager prints it on startup
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "SHA256", value_parser = pixelbomber::tls::parse_pin)]
+    pub manager_pin: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Encode images or a video into a payload file, which is painted by giving it as the only
+    /// image path
+    Compile(CompileArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CompileArgs {
+    /// Where to write the payload
+    pub output: std::path::PathBuf,
 
     /// Image paths
+    #[arg(required = true)]
     pub image: Vec<String>,
 
+    #[command(flatten)]
+    pub encoding: Encoding,
+
+    /// Size of the canvas the payload is made for, the image is cropped at its edges
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+    pub canvas: Option<(u32, u32)>,
+
+    /// Number of chunks the image is split into, use at least as many as painters
+    #[arg(long, value_name = "CHUNKS", default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..))]
+    pub chunks: u32,
+}
+
+fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    let error = || format!("Expected WIDTHxHEIGHT, got \"{size}\"");
+    let (width, height) = size.split_once('x').ok_or_else(error)?;
+    Ok((
+        width.parse().map_err(|_| error())?,
+        height.parse().map_err(|_| error())?,
+    ))
+}
+
+/// How images and videos are encoded into pixel commands
+#[derive(Debug, clap::Args)]
+// keeps this doc comment from replacing the description of the commands it is part of
+#[command(about = None, long_about = None)]
+pub struct Encoding {
     /// Draw width [default: screen width]
     #[arg(short, long, value_name = "PIXELS")]
     pub width: Option<u32>,
@@ -24,18 +182,6 @@ pub struct Args {
     #[arg(short, long, value_name = "PIXELS", default_value = "0")]
     pub y: u32,
 
-    /// Number of concurrent threads [default: CPUs]
-    #[arg(short, long, value_name = "THREADS")]
-    pub count: Option<u32>,
-
-    /// Frames per second with multiple images
-    #[arg(short = 'r', long, value_name = "FPS", default_value = "1")]
-    pub fps: f32,
-
-    /// Disable automatic detection of supported features
-    #[arg(short, long)]
-    pub feature_detection: bool,
-
     /// Enable usage of offset command
     #[arg(short, long)]
     pub offset: bool,
@@ -48,49 +194,73 @@ pub struct Args {
     #[arg(short, long)]
     pub alpha: bool,
 
-    /// Bind address to use for communication
-    #[arg(long)]
-    pub bind_addr: Option<String>,
-
     /// Disable shuffling of draw commands (recommended for video streams)
     #[arg(short, long)]
     pub shuffle: bool,
 
-    /// Number of workers for turning stream images into pixel commands
-    #[arg(long, default_value = "5")]
-    pub workers: u32,
-
     /// Resize images rather than cropping them
     #[arg(long)]
     pub resize: bool,
 
-    /// input is a video (only works with one input file, and requires ffmpeg in $PATH)
-    #[arg(short, long)]
-    pub video: bool,
-
     /// Use the PBxxyyrgba format with le encoding
     #[arg(long)]
     pub le_rgba: bool,
 
-    /// Run continuously (ignore EOF if using stdin)
-    #[arg(long)]
-    pub continuous: bool,
+    /// Use the given binary pixel format instead of the detected one
+    #[arg(long, value_name = "FORMAT")]
+    pub binary_format: Option<BinaryFormat>,
 
-    /// Test green screen for camera. This will print the first pixel of the camera
-    #[arg(long)]
-    pub test_green_screen: bool,
+    /// Use the given bulk pixel format, setting whole runs of pixels at once, instead of the
+    /// detected one
+    #[arg(long, value_name = "FORMAT")]
+    pub bulk_format: Option<BulkFormat>,
 
-    /// Green screen color, obtain by running `pixelbomber --test-green-screen /dev/video0`
-    #[arg(long)]
-    pub green_screen: Option<String>,
+    /// Encode animations as deltas to the previous frame, with a full frame every N frames
+    /// Painters only paint the latest frame, frames they skip because they fall behind (e.g.
+    /// with --stream or slow clients of a manager) stay wrong until the next keyframe. Has no
+    /// effect with --repair and --manager-images, which send images instead
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub keyframe_interval: Option<u32>,
 
-    /// This pixelbomber is only a manager instead of a pixel fluter, listens at the specified port
-    #[arg(long)]
-    pub serve_manager: Option<u16>,
+    /// input is a video (only works with one input file, and requires ffmpeg in $PATH unless it is
+    /// a Y4M video)
+    #[arg(short, long)]
+    pub video: bool,
 
-    /// Listen to the manager at HOST for commands
-    #[arg(long)]
-    pub listen_manager: bool,
+    /// Number of workers for turning stream images into pixel commands
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+    pub workers: u32,
+
+    /// Frames per second with multiple images, animated GIF, APNG and WebP images and videos are
+    /// played with the durations of their frames
+    #[arg(short = 'r', long, value_name = "FPS", default_value = "1",
+        value_parser = crate::control::parse_fps)]
+    pub fps: f32,
+}
+
+impl Encoding {
+    /// The image config given on the command line, before feature detection
+    pub fn image_config(&self, chunks: usize) -> ImageConfig {
+        let mut binary = self.binary_format;
+        if self.le_rgba && binary.is_none() {
+            binary = Some(BinaryFormat::CoordLERGBA);
+        }
+        ImageConfig {
+            width: self.width,
+            height: self.height,
+            x_offset: self.x,
+            y_offset: self.y,
+            offset_usage: self.offset,
+            gray_usage: self.gray,
+            alpha_usage: self.alpha,
+            binary,
+            bulk: self.bulk_format,
+            shuffle: !self.shuffle,
+            chunks,
+            resize: self.resize,
+            datagram_size: None,
+        }
+    }
 }
 
 pub fn parse() -> Args {
@@ -0,0 +1,91 @@
+use std::io::Result;
+
+use image::Rgb;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+use crate::client::{Client, CMD_READ_BUFFER_SIZE};
+
+/// A pixelflut client, supporting most pixelflut commands
+/// This is an async implementation based on tokio
+pub struct AsyncClient {
+    stream: BufStream<TcpStream>,
+}
+
+impl AsyncClient {
+    pub fn new(stream: TcpStream) -> AsyncClient {
+        AsyncClient {
+            stream: BufStream::new(stream),
+        }
+    }
+
+    pub async fn connect(host: &str) -> Result<AsyncClient> {
+        Ok(AsyncClient::new(TcpStream::connect(host).await?))
+    }
+
+    #[inline(always)]
+    pub async fn send_pixel(&mut self, command: &[u8]) -> Result<()> {
+        self.stream.write_all(command).await
+    }
+
+    #[inline(always)]
+    pub async fn flush(&mut self) -> Result<()> {
+        self.stream.flush().await
+    }
+
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await
+    }
+
+    pub async fn read_screen_size(&mut self) -> Result<(u32, u32)> {
+        self.stream.write_all("SIZE\n".as_bytes()).await?;
+        self.stream.flush().await?;
+        let mut buffer = String::with_capacity(CMD_READ_BUFFER_SIZE);
+        self.stream.read_line(&mut buffer).await?;
+        let mut parts = buffer.trim_end().split(' ');
+        _ = parts.next(); // SIZE
+        let width = Client::get_next_u32(&mut parts, 10)?;
+        let height = Client::get_next_u32(&mut parts, 10)?;
+        Ok((width, height))
+    }
+
+    pub async fn read_help(&mut self) -> Result<String> {
+        self.stream.write_all("HELP\nPX 0 0\n".as_bytes()).await?;
+        self.stream.flush().await?;
+        let mut result = String::new();
+        let mut buffer = String::with_capacity(CMD_READ_BUFFER_SIZE);
+        loop {
+            self.stream.read_line(&mut buffer).await?;
+            if buffer.starts_with("PX 0 0") {
+                break;
+            } else {
+                result.push_str(&buffer);
+                buffer.clear();
+            }
+        }
+        Ok(result)
+    }
+
+    pub async fn read_pixel_multi(&mut self, pixel: &[(u32, u32)]) -> Result<Vec<Rgb<u8>>> {
+        for (x, y) in pixel.iter() {
+            self.stream
+                .write_all(format!("PX {x} {y}\n").as_bytes())
+                .await?;
+        }
+        self.stream.flush().await?;
+        let mut buffer = String::with_capacity(CMD_READ_BUFFER_SIZE);
+        let mut result = Vec::with_capacity(pixel.len());
+        for _ in 0..pixel.len() {
+            self.stream.read_line(&mut buffer).await?;
+            result.push(Client::parse_px_response(&buffer)?);
+            buffer.clear();
+        }
+        Ok(result)
+    }
+
+    pub async fn read_pixel(&mut self, x: u32, y: u32) -> Result<Rgb<u8>> {
+        Ok(self.read_pixel_multi(&[(x, y)]).await?[0])
+    }
+}
@@ -0,0 +1,188 @@
+//! Disk cache of encoded animations, so long videos don't have to be encoded again on every start
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bincode::config::standard;
+use bincode::{Decode, Encode};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::image_handler::{CommandLib, ImageConfig};
+
+/// Cache files of other versions are ignored, as the encoding of the commands might differ
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXTENSION: &str = "pbcache";
+
+/// SHA-256 of the input files
+pub type SourceHash = [u8; 32];
+
+/// Hash the content of the files at `paths`, in order
+pub fn hash_sources(paths: &[impl AsRef<Path>]) -> io::Result<SourceHash> {
+    let mut hasher = Sha256::new();
+    for path in paths {
+        let length = io::copy(&mut File::open(path)?, &mut hasher)?;
+        // keeps the content of one file from being mistaken for the start of the next
+        hasher.update(length.to_le_bytes());
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Everything the encoded commands depend on
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct CacheKey {
+    pub source: SourceHash,
+    pub config: ImageConfig,
+    pub keyframe_interval: Option<usize>,
+}
+
+/// A directory of encoded animations, with one compressed file per input and config
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Cache {
+        Cache { dir: dir.into() }
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        let encoded = bincode::encode_to_vec(key, standard()).expect("Unable to encode cache key");
+        let name =
+            Sha256::digest(encoded)
+                .iter()
+                .fold(String::with_capacity(64), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                });
+        self.dir.join(name).with_extension(EXTENSION)
+    }
+
+    /// Load the animation stored for `key`, `None` if there is none or it can't be read
+    pub fn load(&self, key: &CacheKey) -> Option<CommandLib> {
+        let path = self.path(key);
+        if !path.is_file() {
+            return None;
+        }
+        match read(&path, key) {
+            Ok(library) => library,
+            Err(err) => {
+                warn!("Ignoring unreadable cache file {} ({err})", path.display());
+                None
+            }
+        }
+    }
+
+    /// Store the animation for `key`. It is written to a temporary file first, so other runs
+    /// never read a partially written file
+    pub fn store(&self, key: &CacheKey, library: &CommandLib) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let temporary = path.with_extension(format!("{EXTENSION}.{}", std::process::id()));
+        let encoded = bincode::encode_to_vec((VERSION, key, library), standard())?;
+        fs::write(&temporary, zstd::encode_all(&encoded[..], 3)?)?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+fn read(path: &Path, key: &CacheKey) -> Result<Option<CommandLib>, Box<dyn Error>> {
+    let data = zstd::decode_all(File::open(path)?)?;
+    let (version, read): (String, usize) = bincode::decode_from_slice(&data, standard())?;
+    if version != VERSION {
+        return Ok(None);
+    }
+    let ((stored, library), _): ((CacheKey, CommandLib), usize) =
+        bincode::decode_from_slice(&data[read..], standard())?;
+    Ok((stored == *key).then_some(library))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handler::{ImageConfigBuilder, TimedCommand};
+    use std::time::Duration;
+
+    /// An empty cache directory, only used by the test `name`
+    fn cache(name: &str) -> Cache {
+        let dir = std::env::temp_dir().join(format!("pixelbomber-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Cache::new(dir)
+    }
+
+    fn key() -> CacheKey {
+        CacheKey {
+            source: [1; 32],
+            config: ImageConfigBuilder::new().build(),
+            keyframe_interval: None,
+        }
+    }
+
+    fn library() -> CommandLib {
+        vec![TimedCommand::new(
+            vec![b"PX 0 0 ffffff\n".to_vec()],
+            Some(Duration::from_millis(40)),
+        )]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let cache = cache("roundtrip");
+        assert!(cache.load(&key()).is_none());
+        cache.store(&key(), &library()).unwrap();
+        let loaded = cache.load(&key()).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].command, library()[0].command);
+        assert_eq!(loaded[0].duration, library()[0].duration);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_covers_everything() {
+        let cache = cache("key");
+        cache.store(&key(), &library()).unwrap();
+        let other_source = CacheKey { source: [2; 32], ..key() };
+        let other_config = CacheKey {
+            config: ImageConfigBuilder::new().x_offset(10).build(),
+            ..key()
+        };
+        let other_interval = CacheKey { keyframe_interval: Some(10), ..key() };
+        for other in [other_source, other_config, other_interval] {
+            assert_ne!(cache.path(&other), cache.path(&key()));
+            assert!(cache.load(&other).is_none());
+        }
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_other_version() {
+        let cache = cache("version");
+        fs::create_dir_all(&cache.dir).unwrap();
+        let encoded = bincode::encode_to_vec(("0.0.0", key(), library()), standard()).unwrap();
+        fs::write(cache.path(&key()), zstd::encode_all(&encoded[..], 3).unwrap()).unwrap();
+        assert!(cache.load(&key()).is_none());
+        // unreadable files are ignored as well
+        fs::write(cache.path(&key()), b"garbage").unwrap();
+        assert!(cache.load(&key()).is_none());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_hash_sources() {
+        let cache = cache("hash");
+        fs::create_dir_all(&cache.dir).unwrap();
+        let (a, b) = (cache.dir.join("a"), cache.dir.join("b"));
+        fs::write(&a, b"ab").unwrap();
+        fs::write(&b, b"c").unwrap();
+        let hash = hash_sources(&[&a, &b]).unwrap();
+        assert_eq!(hash, hash_sources(&[&a, &b]).unwrap());
+        assert_ne!(hash, hash_sources(&[&b, &a]).unwrap());
+        // moving a byte from one file to the next changes the hash
+        fs::write(&a, b"a").unwrap();
+        fs::write(&b, b"bc").unwrap();
+        assert_ne!(hash, hash_sources(&[&a, &b]).unwrap());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
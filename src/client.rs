@@ -7,25 +7,50 @@ use std::{
 use bufstream::BufStream;
 use image::Rgb;
 
-const CMD_READ_BUFFER_SIZE: usize = 1024;
+use crate::{connection::Connection, Transport};
+
+pub(crate) const CMD_READ_BUFFER_SIZE: usize = 1024;
 
 /// A pixelflut client, supporting most pixelflut commands
-/// This is a sync implementation
-pub struct Client {
-    stream: BufStream<TcpStream>,
+/// This is a sync implementation, generic over the transport it talks over
+pub struct Client<T: Transport = Connection> {
+    stream: BufStream<T>,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    pub fn connect(host: &str) -> Result<Client> {
+        Ok(Client::new(TcpStream::connect(host)?.into()))
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_next_u32(split: &mut Split<char>, radix: u32) -> Result<u32> {
+        split
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No data"))
+            .and_then(|data| {
+                u32::from_str_radix(data, radix)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+            })
+    }
+
+    #[inline(always)]
+    pub(crate) fn parse_px_response(response: &str) -> Result<Rgb<u8>> {
+        let mut parts = response.trim_end().split(' ');
+        _ = parts.next(); // PX
+        _ = parts.next(); // x
+        _ = parts.next(); // y
+        let color: u32 = Client::get_next_u32(&mut parts, 16)?;
+        Ok(Rgb([(color >> 16) as u8, (color >> 8) as u8, color as u8]))
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(stream: T) -> Client<T> {
         Client {
             stream: BufStream::new(stream),
         }
     }
 
-    pub fn connect(host: &str) -> Result<Client> {
-        Ok(Client::new(TcpStream::connect(host)?))
-    }
-
     #[inline(always)]
     pub fn send_pixel(&mut self, command: &[u8]) -> Result<()> {
         self.stream.write_all(command)
@@ -44,21 +69,10 @@ impl Client {
     }
 
     pub fn shutdown(&self) -> Result<()> {
-        self.stream.get_ref().shutdown(std::net::Shutdown::Both)?;
+        self.stream.get_ref().shutdown()?;
         Ok(())
     }
 
-    #[inline(always)]
-    fn get_next_u32(split: &mut Split<char>, radix: u32) -> Result<u32> {
-        split
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No data"))
-            .and_then(|data| {
-                u32::from_str_radix(data, radix)
-                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))
-            })
-    }
-
     pub fn read_screen_size(&mut self) -> Result<(u32, u32)> {
         self.stream.write_all("SIZE\n".as_bytes())?;
         self.stream.flush()?;
@@ -66,8 +80,8 @@ impl Client {
         self.stream.read_line(&mut buffer)?;
         let mut parts = buffer.trim_end().split(' ');
         _ = parts.next(); // SIZE
-        let width = Self::get_next_u32(&mut parts, 10)?;
-        let height = Self::get_next_u32(&mut parts, 10)?;
+        let width = Client::get_next_u32(&mut parts, 10)?;
+        let height = Client::get_next_u32(&mut parts, 10)?;
         Ok((width, height))
     }
 
@@ -88,16 +102,6 @@ impl Client {
         Ok(result)
     }
 
-    #[inline(always)]
-    fn parse_px_response(response: &str) -> Result<Rgb<u8>> {
-        let mut parts = response.trim_end().split(' ');
-        _ = parts.next(); // PX
-        _ = parts.next(); // x
-        _ = parts.next(); // y
-        let color: u32 = Self::get_next_u32(&mut parts, 16)?;
-        Ok(Rgb([(color >> 16) as u8, (color >> 8) as u8, color as u8]))
-    }
-
     pub fn read_pixel_multi(&mut self, pixel: &[(u32, u32)]) -> Result<Vec<Rgb<u8>>> {
         for (x, y) in pixel.iter() {
             self.stream.write_all(format!("PX {x} {y}\n").as_bytes())?;
@@ -107,7 +111,7 @@ impl Client {
         let mut result = Vec::with_capacity(pixel.len());
         for _ in 0..pixel.len() {
             self.stream.read_line(&mut buffer)?;
-            result.push(Self::parse_px_response(&buffer)?);
+            result.push(Client::parse_px_response(&buffer)?);
             buffer.clear();
         }
         Ok(result)
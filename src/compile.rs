@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use pixelbomber::payload::Payload;
+
+use crate::arg_handler::CompileArgs;
+use crate::manager::FileInput;
+
+/// Encode the images or video of `args`, and write them to a payload file
+pub fn compile(args: CompileArgs) -> Result<(), String> {
+    let encoding = &args.encoding;
+    let mut config = encoding.image_config(args.chunks as usize);
+    if let Some(canvas) = args.canvas {
+        config = config.crop_to_canvas(canvas);
+    }
+    let input = FileInput {
+        video: encoding.video,
+        images: false,
+        workers: encoding.workers as usize,
+        keyframe_interval: encoding.keyframe_interval.map(|interval| interval as usize),
+        stream: None,
+        cache: None,
+    };
+    let mut library = input.encode(&args.image, config)?;
+    // the payload is played with the frame rate it was compiled with
+    let duration = Duration::from_secs_f32(1.0 / encoding.fps);
+    for frame in &mut library {
+        frame.duration.get_or_insert(duration);
+    }
+    let payload = Payload {
+        config,
+        canvas: args.canvas,
+        library,
+    };
+    payload
+        .write(&args.output)
+        .map_err(|err| format!("Unable to write {} ({err})", args.output.display()))?;
+    println!(
+        "Wrote {} frame(s) to {}",
+        payload.library.len(),
+        args.output.display()
+    );
+    Ok(())
+}
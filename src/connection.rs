@@ -0,0 +1,189 @@
+use std::{
+    io::{Error, Read, Result, Write},
+    net::TcpStream,
+};
+
+#[cfg(feature = "websocket")]
+use std::io::ErrorKind;
+#[cfg(feature = "websocket")]
+use tungstenite::{handshake::HandshakeError, Message, WebSocket};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::Transport;
+
+/// Upper bound for the payload of a single WebSocket message, see `ImageConfig::datagram_size`
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// A stream based connection to a pixelflut server
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WebSocketStream>),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Transport for Connection {
+    fn shutdown(&self) -> Result<()> {
+        match self {
+            Connection::Tcp(stream) => Transport::shutdown(stream),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => stream.shutdown(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Transport::shutdown(stream.as_ref()),
+        }
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        match self {
+            Connection::Tcp(stream) => Transport::take_error(stream),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => stream.take_error(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Transport::take_error(stream.as_ref()),
+        }
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        Connection::Tcp(stream)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<TlsStream> for Connection {
+    fn from(stream: TlsStream) -> Self {
+        Connection::Tls(Box::new(stream))
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<WebSocketStream> for Connection {
+    fn from(stream: WebSocketStream) -> Self {
+        Connection::WebSocket(Box::new(stream))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Exposes the pixelflut protocol over a WebSocket as a byte stream
+/// Every write is sent as one binary message, so a chunk of commands is never split. Responses of
+/// the server are read from text or binary messages
+#[cfg(feature = "websocket")]
+pub struct WebSocketStream {
+    socket: WebSocket<TcpStream>,
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+}
+
+#[cfg(feature = "websocket")]
+fn to_io_error(err: tungstenite::Error) -> Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            ErrorKind::BrokenPipe.into()
+        }
+        err => Error::other(err),
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl WebSocketStream {
+    /// Perform the WebSocket handshake for `url` on an already connected stream
+    pub fn connect(url: &str, stream: TcpStream) -> Result<WebSocketStream> {
+        let (socket, _) = tungstenite::client(url, stream).map_err(|err| match err {
+            HandshakeError::Failure(err) => to_io_error(err),
+            HandshakeError::Interrupted(_) => Error::new(
+                ErrorKind::WouldBlock,
+                "WebSocket handshake needs a blocking stream",
+            ),
+        })?;
+        Ok(WebSocketStream {
+            socket,
+            read_buffer: Vec::new(),
+            read_pos: 0,
+        })
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Transport for WebSocketStream {
+    fn shutdown(&self) -> Result<()> {
+        Transport::shutdown(self.socket.get_ref())
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        Transport::take_error(self.socket.get_ref())
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.read_pos == self.read_buffer.len() {
+            let data = match self.socket.read() {
+                Ok(Message::Text(text)) => text.as_bytes().to_vec(),
+                Ok(Message::Binary(data)) => data.to_vec(),
+                Ok(Message::Close(_)) => return Ok(0),
+                // pings are answered by tungstenite
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(0),
+                Err(err) => return Err(to_io_error(err)),
+            };
+            self.read_buffer = data;
+            self.read_pos = 0;
+        }
+        let len = buf.len().min(self.read_buffer.len() - self.read_pos);
+        buf[..len].copy_from_slice(&self.read_buffer[self.read_pos..self.read_pos + len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.socket
+            .write(Message::binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.socket.flush().map_err(to_io_error)
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use log::warn;
+use pixelbomber::{image_handler::ImageConfig, service::Service};
+
+const COMMANDS: &str =
+    "image <PATH>..., offset <X> <Y>, size <WIDTH> <HEIGHT>, fps <FPS>, threads <N>, status";
+
+/// Sends frames to the service, called in a loop
+pub type Source = Box<dyn FnMut(&mut Service)>;
+
+/// Creates a source for the images at the given paths, with the given config and fps
+pub type Loader = Box<dyn Fn(&[String], ImageConfig, f32) -> Result<Source, String>>;
+
+/// A change requested over the control interface
+#[derive(Debug)]
+pub enum Request {
+    Image(Vec<String>),
+    Offset(u32, u32),
+    Size(u32, u32),
+    Fps(f32),
+    Threads(usize),
+    Status,
+}
+
+type Reply = Result<String, String>;
+
+pub type Requests = Receiver<(Request, Sender<Reply>)>;
+
+fn number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("invalid number \"{word}\""))
+}
+
+/// Frame rates have to be positive, also used for `--fps`
+pub fn parse_fps(word: &str) -> Result<f32, String> {
+    let fps: f32 = number(word)?;
+    if !(fps > 0.0 && fps.is_finite()) {
+        return Err("fps must be positive".to_string());
+    }
+    Ok(fps)
+}
+
+fn parse(line: &str) -> Result<Request, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["image", paths @ ..] if !paths.is_empty() => Ok(Request::Image(
+            paths.iter().map(|path| path.to_string()).collect(),
+        )),
+        ["offset", x, y] => Ok(Request::Offset(number(x)?, number(y)?)),
+        ["size", width, height] => {
+            let (width, height) = (number(width)?, number(height)?);
+            if width == 0 || height == 0 {
+                return Err("size must not be zero".to_string());
+            }
+            Ok(Request::Size(width, height))
+        }
+        ["fps", fps] => Ok(Request::Fps(parse_fps(fps)?)),
+        ["threads", threads] => match number(threads)? {
+            0 => Err("at least one thread is needed".to_string()),
+            threads => Ok(Request::Threads(threads)),
+        },
+        ["status"] => Ok(Request::Status),
+        _ => Err(format!("unknown command, expected one of: {COMMANDS}")),
+    }
+}
+
+/// Listen for control connections on localhost
+/// Every line is one command, and is answered with a line starting with either `OK` or `ERR`
+pub fn listen(port: u16) -> io::Result<Requests> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let (requests, output) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let requests = requests.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve(stream, requests) {
+                    warn!("Control connection failed ({err})");
+                }
+            });
+        }
+    });
+    Ok(output)
+}
+
+fn serve(stream: TcpStream, requests: Sender<(Request, Sender<Reply>)>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = parse(&line).and_then(|request| {
+            let (reply, result) = channel();
+            requests
+                .send((request, reply))
+                .map_err(|_| "service stopped".to_string())?;
+            result.recv().map_err(|_| "service stopped".to_string())?
+        });
+        match reply {
+            Ok(message) => writeln!(writer, "OK {message}")?,
+            Err(message) => writeln!(writer, "ERR {message}")?,
+        }
+    }
+    Ok(())
+}
+
+/// What is currently painted, as far as the control interface can change it
+pub struct Settings {
+    pub paths: Vec<String>,
+    pub config: ImageConfig,
+    pub fps: f32,
+    /// Size requested by the user, the config is cropped to the canvas
+    pub size: (Option<u32>, Option<u32>),
+    /// Size of the canvas, if it was detected
+    pub canvas: Option<(u32, u32)>,
+}
+
+/// Wraps a source, and applies requests of the control interface between frames
+pub struct Controller {
+    requests: Requests,
+    source: Source,
+    /// Only inputs loaded from files can be replaced
+    loader: Option<Loader>,
+    /// The input was encoded ahead of time, so its offset and size are fixed
+    prepared: bool,
+    settings: Settings,
+}
+
+impl Controller {
+    pub fn new(requests: Requests, source: Source, settings: Settings) -> Controller {
+        Controller {
+            requests,
+            source,
+            loader: None,
+            prepared: false,
+            settings,
+        }
+    }
+
+    /// Allow replacing the input, and encoding it again when the config changes
+    pub fn loader(mut self, loader: Loader) -> Controller {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// Refuse to change the offset and size, for inputs that were encoded ahead of time
+    pub fn prepared(mut self) -> Controller {
+        self.prepared = true;
+        self
+    }
+
+    /// Handle all pending requests, and send the next frame
+    pub fn run(&mut self, service: &mut Service) {
+        while let Ok((request, reply)) = self.requests.try_recv() {
+            let _ = reply.send(self.handle(service, request));
+        }
+        (self.source)(service)
+    }
+
+    fn handle(&mut self, service: &mut Service, request: Request) -> Reply {
+        match request {
+            Request::Image(paths) => {
+                self.reload(&paths, self.settings.config, self.settings.fps)?;
+                self.settings.paths = paths;
+                Ok(String::new())
+            }
+            Request::Offset(x, y) => {
+                let config = ImageConfig {
+                    x_offset: x,
+                    y_offset: y,
+                    ..self.settings.config
+                };
+                self.reconfigure(service, config)
+            }
+            Request::Size(width, height) => {
+                self.settings.size = (Some(width), Some(height));
+                self.reconfigure(service, self.settings.config)
+            }
+            Request::Fps(fps) => {
+                let paths = self.settings.paths.clone();
+                self.reload(&paths, self.settings.config, fps)?;
+                self.settings.fps = fps;
+                Ok(String::new())
+            }
+            Request::Threads(threads) => service
+                .set_threads(threads)
+                .map(|()| format!("{threads} painter(s)"))
+                .map_err(|err| err.to_string()),
+            Request::Status => Ok(status(service)),
+        }
+    }
+
+    /// Apply a new config, and crop it to the canvas
+    fn reconfigure(&mut self, service: &mut Service, mut config: ImageConfig) -> Reply {
+        if self.prepared {
+            return Err("the input is already encoded, it can't be moved or resized".to_string());
+        }
+        let (width, height) = self.settings.size;
+        config.width = width;
+        config.height = height;
+        if let Some(canvas) = self.settings.canvas {
+            config = config.crop_to_canvas(canvas);
+        }
+        // images loaded from files were encoded with the old config
+        if self.loader.is_some() {
+            let paths = self.settings.paths.clone();
+            self.reload(&paths, config, self.settings.fps)?;
+        }
+        service.change_image_config(config);
+        self.settings.config = config;
+        Ok(format!(
+            "{}x{} at {},{}",
+            config
+                .width
+                .map_or("auto".to_string(), |width| width.to_string()),
+            config
+                .height
+                .map_or("auto".to_string(), |height| height.to_string()),
+            config.x_offset,
+            config.y_offset,
+        ))
+    }
+
+    fn reload(&mut self, paths: &[String], config: ImageConfig, fps: f32) -> Result<(), String> {
+        let Some(loader) = &self.loader else {
+            return Err("the input can't be replaced at runtime".to_string());
+        };
+        self.source = loader(paths, config, fps)?;
+        Ok(())
+    }
+}
+
+fn status(service: &Service) -> String {
+    if service.is_manager() {
+        service.manager_status().to_string()
+    } else {
+        service.stats().to_string()
+    }
+}
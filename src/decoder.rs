@@ -0,0 +1,298 @@
+//! In process decoding of animations and videos, without ffmpeg (with the `animation` feature)
+//!
+//! Animated GIF, APNG and WebP files are decoded with the animation decoders of the `image`
+//! crate, uncompressed YUV4MPEG2 (`.y4m`) videos are decoded here
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+use image::{AnimationDecoder, DynamicImage, ImageError, ImageFormat, ImageResult, RgbImage};
+
+/// Frames without a delay are shown this long, like browsers do
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+/// Frame rate of videos that don't specify one
+const DEFAULT_FPS: (u64, u64) = (25, 1);
+const Y4M_MAGIC: &[u8] = b"YUV4MPEG2 ";
+
+/// A single decoded frame, and how long it is shown
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay: Duration,
+}
+
+/// The frames of an animation, decoded one after another
+pub type Frames = Box<dyn Iterator<Item = ImageResult<Frame>>>;
+
+/// Open an animation or video that can be decoded in process
+/// Returns `None` for still images and files that need ffmpeg
+pub fn open(path: &Path) -> ImageResult<Option<Frames>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(Y4M_MAGIC) {
+        return Ok(Some(Box::new(Y4mDecoder::new(reader)?)));
+    }
+    let frames = match image::guess_format(magic) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(reader)?.into_frames(),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames()
+        }
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(Box::new(frames.map(|frame| frame.map(Frame::from)))))
+}
+
+impl From<image::Frame> for Frame {
+    fn from(frame: image::Frame) -> Frame {
+        let delay = match Duration::from(frame.delay()) {
+            Duration::ZERO => DEFAULT_DELAY,
+            delay => delay,
+        };
+        Frame {
+            image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            delay,
+        }
+    }
+}
+
+/// How long a frame is shown at a frame rate of `num / den`, `None` if that rounds to nothing
+fn frame_delay((num, den): (u64, u64)) -> Option<Duration> {
+    let nanos = u128::from(den) * 1_000_000_000 / u128::from(num);
+    u64::try_from(nanos)
+        .ok()
+        .filter(|&nanos| nanos > 0)
+        .map(Duration::from_nanos)
+}
+
+fn invalid(message: impl Into<String>) -> ImageError {
+    ImageError::IoError(io::Error::new(io::ErrorKind::InvalidData, message.into()))
+}
+
+/// Chroma subsampling of a Y4M video
+#[derive(Copy, Clone)]
+enum Chroma {
+    C420,
+    C422,
+    C444,
+    Mono,
+}
+
+impl Chroma {
+    fn parse(colorspace: &str) -> ImageResult<Chroma> {
+        match colorspace {
+            "420" | "420jpeg" | "420paldv" | "420mpeg2" => Ok(Chroma::C420),
+            "422" => Ok(Chroma::C422),
+            "444" => Ok(Chroma::C444),
+            "mono" => Ok(Chroma::Mono),
+            _ => Err(invalid(format!("unsupported y4m colorspace {colorspace}"))),
+        }
+    }
+
+    /// Size of a chroma plane for a frame of the given size
+    fn plane_size(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Chroma::C420 => (width.div_ceil(2), height.div_ceil(2)),
+            Chroma::C422 => (width.div_ceil(2), height),
+            Chroma::C444 => (width, height),
+            Chroma::Mono => (0, 0),
+        }
+    }
+}
+
+/// Decoder for uncompressed YUV4MPEG2 videos with 8 bit samples, as written by
+/// `ffmpeg -i <VIDEO> -pix_fmt yuv420p <VIDEO>.y4m`
+struct Y4mDecoder<R: BufRead> {
+    reader: R,
+    width: usize,
+    height: usize,
+    chroma: Chroma,
+    delay: Duration,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> Y4mDecoder<R> {
+    fn new(mut reader: R) -> ImageResult<Y4mDecoder<R>> {
+        let header = read_line(&mut reader)?.ok_or_else(|| invalid("empty y4m file"))?;
+        let (mut width, mut height) = (0, 0);
+        let mut fps = DEFAULT_FPS;
+        let mut chroma = Chroma::C420;
+        for param in header.split(' ').skip(1).filter(|param| !param.is_empty()) {
+            let Some((tag, value)) = param.split_at_checked(1) else {
+                return Err(invalid("invalid y4m parameter"));
+            };
+            match tag {
+                "W" => width = value.parse().map_err(|_| invalid("invalid y4m width"))?,
+                "H" => height = value.parse().map_err(|_| invalid("invalid y4m height"))?,
+                "F" => {
+                    let rate = value
+                        .split_once(':')
+                        .and_then(|(num, den)| Some((num.parse().ok()?, den.parse().ok()?)));
+                    match rate {
+                        Some((num, den)) if num > 0 && den > 0 => fps = (num, den),
+                        _ => return Err(invalid("invalid y4m frame rate")),
+                    }
+                }
+                "C" => chroma = Chroma::parse(value)?,
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err(invalid("y4m file without frame size"));
+        }
+        let delay = frame_delay(fps).ok_or_else(|| invalid("invalid y4m frame rate"))?;
+        let (chroma_width, chroma_height) = chroma.plane_size(width, height);
+        Ok(Y4mDecoder {
+            reader,
+            width,
+            height,
+            chroma,
+            delay,
+            buffer: vec![0; width * height + 2 * chroma_width * chroma_height],
+        })
+    }
+
+    fn decode(&mut self) -> ImageResult<Option<Frame>> {
+        let Some(header) = read_line(&mut self.reader)? else {
+            return Ok(None);
+        };
+        if !header.starts_with("FRAME") {
+            return Err(invalid("missing y4m frame header"));
+        }
+        self.reader.read_exact(&mut self.buffer)?;
+        let (width, height) = (self.width, self.height);
+        let (chroma_width, chroma_height) = self.chroma.plane_size(width, height);
+        let (luma, chroma) = self.buffer.split_at(width * height);
+        let (u, v) = chroma.split_at(chroma_width * chroma_height);
+        let mut image = RgbImage::new(width as u32, height as u32);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let (x, y) = (x as usize, y as usize);
+            let (cx, cy) = match self.chroma {
+                Chroma::C420 => (x / 2, y / 2),
+                Chroma::C422 => (x / 2, y),
+                Chroma::C444 => (x, y),
+                Chroma::Mono => (0, 0),
+            };
+            let (u, v) = match self.chroma {
+                Chroma::Mono => (128, 128),
+                _ => (u[cy * chroma_width + cx], v[cy * chroma_width + cx]),
+            };
+            pixel.0 = yuv_to_rgb(luma[y * width + x], u, v);
+        }
+        Ok(Some(Frame {
+            image: DynamicImage::ImageRgb8(image),
+            delay: self.delay,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Y4mDecoder<R> {
+    type Item = ImageResult<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode().transpose()
+    }
+}
+
+/// Read a header line, returns `None` at the end of the file
+fn read_line(reader: &mut impl BufRead) -> ImageResult<Option<String>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("truncated y4m header"));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("invalid y4m header"))
+}
+
+/// Convert limited range BT.601 YUV to RGB
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, RgbaImage};
+
+    #[test]
+    fn test_y4m_header() {
+        let data = b"YUV4MPEG2 W4 H2 F30000:1001 Ip A1:1 C444\n".to_vec();
+        let decoder = Y4mDecoder::new(&data[..]).unwrap();
+        assert_eq!((decoder.width, decoder.height), (4, 2));
+        assert!(matches!(decoder.chroma, Chroma::C444));
+        assert_eq!(decoder.delay, Duration::from_secs(1001) / 30000);
+        assert_eq!(decoder.buffer.len(), 3 * 4 * 2);
+        // 420 is the default, and 25 fps without a frame rate
+        let decoder = Y4mDecoder::new(&b"YUV4MPEG2 W3 H3\n"[..]).unwrap();
+        assert!(matches!(decoder.chroma, Chroma::C420));
+        assert_eq!(decoder.delay, Duration::from_millis(40));
+        assert_eq!(decoder.buffer.len(), 9 + 2 * 2 * 2);
+        assert!(Y4mDecoder::new(&b"YUV4MPEG2 W3\n"[..]).is_err());
+        assert!(Y4mDecoder::new(&b"YUV4MPEG2 W3 H3 F0:1\n"[..]).is_err());
+        // numerators beyond 32 bit, and frame rates too high to show a frame for a nanosecond
+        let decoder = Y4mDecoder::new(&b"YUV4MPEG2 W3 H3 F4294967296:4294967296\n"[..]).unwrap();
+        assert_eq!(decoder.delay, Duration::from_secs(1));
+        assert!(Y4mDecoder::new(&b"YUV4MPEG2 W3 H3 F4294967296000:1\n"[..]).is_err());
+        // tags are single bytes, other characters must not panic
+        assert!(Y4mDecoder::new("YUV4MPEG2 W3 H3 \u{e4}1\n".as_bytes()).is_err());
+        assert!(Y4mDecoder::new(&b"YUV4MPEG2 W3 H3 C411\n"[..]).is_err());
+        assert!(Y4mDecoder::new(&b"YUV4MPEG2 W3 H3"[..]).is_err());
+    }
+
+    #[test]
+    fn test_y4m_frames() {
+        let mut data = b"YUV4MPEG2 W2 H2 C420\nFRAME\n".to_vec();
+        data.extend_from_slice(&[16, 235, 235, 16, 128, 128]);
+        let mut decoder = Y4mDecoder::new(&data[..]).unwrap();
+        let frame = decoder.next().unwrap().unwrap();
+        let image = frame.image.to_rgb8();
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(1, 0).0, [255, 255, 255]);
+        assert!(decoder.next().is_none());
+        // a frame that is cut off
+        let mut decoder = Y4mDecoder::new(&data[..data.len() - 1]).unwrap();
+        assert!(decoder.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_yuv_to_rgb() {
+        assert_eq!(yuv_to_rgb(16, 128, 128), [0, 0, 0]);
+        assert_eq!(yuv_to_rgb(235, 128, 128), [255, 255, 255]);
+        assert_eq!(yuv_to_rgb(81, 90, 240), [255, 0, 0]);
+        // out of range values are clamped
+        assert_eq!(yuv_to_rgb(0, 128, 128), [0, 0, 0]);
+        assert_eq!(yuv_to_rgb(255, 128, 128), [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_zero_delay() {
+        let image = RgbaImage::new(1, 1);
+        let frame = image::Frame::from_parts(image.clone(), 0, 0, Delay::from_numer_denom_ms(0, 1));
+        assert_eq!(Frame::from(frame).delay, DEFAULT_DELAY);
+        let frame = image::Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(20, 1));
+        assert_eq!(Frame::from(frame).delay, Duration::from_millis(20));
+    }
+}
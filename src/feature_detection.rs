@@ -1,5 +1,6 @@
 use crate::client::Client;
-use crate::image_handler::BinaryFormat;
+use crate::image_handler::{BinaryFormat, BulkFormat};
+use crate::Transport;
 use std::io::Result;
 
 /// Detected feature set of a pixelflut server.
@@ -12,15 +13,27 @@ pub struct Features {
     pub offset: bool,
     /// If the `PX x y gg` command is supported. NOTE: this is derived from the HELP command
     pub px_gray: bool,
-    /// If and what binary format the server uses
+    /// If and what binary format the server uses. NOTE: this is derived from the HELP command, if
+    /// the server lists multiple formats, the first of `BINARY_PREFERENCE` that can address the
+    /// whole canvas is used
     pub binary: Option<BinaryFormat>,
+    /// If and what bulk format the server uses. NOTE: this is derived from the HELP command
+    pub bulk: Option<BulkFormat>,
 }
 
+/// Binary formats in the order they are picked if a server supports several of them
+const BINARY_PREFERENCE: [BinaryFormat; 4] = [
+    BinaryFormat::CoordLERGBA,
+    BinaryFormat::CoordBERGBA,
+    BinaryFormat::CoordLERGB,
+    BinaryFormat::Coord32LERGBA,
+];
+
 /// Detect the features supported by a server
 /// NOTE: command detection is based on the `HELP` command, and might not work
 /// If you do notice that a server has a certain feature, but this is not reflected in the result,
 /// feel free to open an issue
-pub fn feature_detection(client: &mut Client) -> Result<Features> {
+pub fn feature_detection<T: Transport>(client: &mut Client<T>) -> Result<Features> {
     let (width, height) = client.read_screen_size()?;
     let mut features = Features {
         width,
@@ -28,7 +41,9 @@ pub fn feature_detection(client: &mut Client) -> Result<Features> {
         offset: false,
         px_gray: false,
         binary: None,
+        bulk: None,
     };
+    let mut binary = Vec::new();
     let help_text = client.read_help()?;
     for line in help_text.split('\n') {
         let lowered = line.to_lowercase();
@@ -39,10 +54,127 @@ pub fn feature_detection(client: &mut Client) -> Result<Features> {
         // breakwater format and Wellenbrecher format
         } else if trimmed.starts_with("px x y gg") || trimmed.starts_with("grayscale") {
             features.px_gray = true
-        // pixelpwner-server and breakwater format
-        } else if trimmed.contains("pbxyrgba") || trimmed.contains("pbxxyyrgba") {
-            features.binary = Some(BinaryFormat::CoordLERGBA)
+        // bulk rows of pixels, `PRxxyyllrgba...`
+        } else if words(trimmed).any(|word| word == "prxxyyllrgba") {
+            features.bulk = Some(BulkFormat::RowLERGBA).filter(|_| fits_u16(width, height))
+        } else {
+            binary.extend(binary_format(trimmed));
         }
     }
+    features.binary = BINARY_PREFERENCE
+        .into_iter()
+        .filter(|format| binary.contains(format))
+        .find(|format| *format == BinaryFormat::Coord32LERGBA || fits_u16(width, height));
     Ok(features)
 }
+
+/// The binary format a lowercase line of the help text describes, if any
+fn binary_format(line: &str) -> Option<BinaryFormat> {
+    let big_endian = line.contains("big endian") || line.contains("big-endian");
+    words(line).find_map(|word| match word {
+        // pixelpwner-server and breakwater format, little endian unless stated otherwise
+        "pbxyrgba" | "pbxxyyrgba" if big_endian => Some(BinaryFormat::CoordBERGBA),
+        "pbxyrgba" | "pbxxyyrgba" => Some(BinaryFormat::CoordLERGBA),
+        "pbxyrgb" | "pbxxyyrgb" => Some(BinaryFormat::CoordLERGB),
+        "pbxxxxyyyyrgba" => Some(BinaryFormat::Coord32LERGBA),
+        _ => None,
+    })
+}
+
+/// The words of a line of the help text, without punctuation
+fn words(line: &str) -> impl Iterator<Item = &str> {
+    line.split(|c: char| !c.is_ascii_alphanumeric())
+}
+
+/// If 16 bit coordinates can address every pixel of a `width` x `height` canvas
+fn fits_u16(width: u32, height: u32) -> bool {
+    width.max(height) <= 1 << 16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    /// Excerpt of the help text of breakwater (https://github.com/sbernauer/breakwater)
+    const BREAKWATER_HELP: &str = "\
+Pixelflut server powered by breakwater https://github.com/sbernauer/breakwater
+Available commands:
+HELP: Show this help
+PX x y rrggbb: Color the pixel (x,y) with the given hexadecimal color rrggbb
+PX x y gg: Color the pixel (x,y) with the hexadecimal color gggggg
+PX x y: Get the color value of the pixel (x,y)
+SIZE: Get the size of the drawing surface, e.g. `SIZE 1920 1080`
+OFFSET x y: Apply offset (x,y) to all further pixel draws on this connection
+PBxyrgba: Binary version of the PX command. x and y are little-endian 16 bit coordinates
+";
+
+    /// Run the feature detection against a server answering with `help`
+    fn detect(help: &str) -> Features {
+        detect_with_size("1920 1080", help)
+    }
+
+    fn detect_with_size(size: &str, help: &str) -> Features {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        write!(server, "SIZE {size}\n{help}PX 0 0 000000\n").unwrap();
+        feature_detection(&mut Client::new(client)).unwrap()
+    }
+
+    #[test]
+    fn test_breakwater() {
+        let features = detect(BREAKWATER_HELP);
+        assert_eq!((features.width, features.height), (1920, 1080));
+        assert!(features.offset);
+        assert!(features.px_gray);
+        assert_eq!(features.binary, Some(BinaryFormat::CoordLERGBA));
+    }
+
+    #[test]
+    fn test_plain_server() {
+        let features = detect("HELP text\n");
+        assert!(!features.offset);
+        assert!(!features.px_gray);
+        assert_eq!(features.binary, None);
+        assert_eq!(features.bulk, None);
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let features = detect("PBxyrgba: x and y are big-endian 16 bit coordinates\n");
+        assert_eq!(features.binary, Some(BinaryFormat::CoordBERGBA));
+    }
+
+    #[test]
+    fn test_rgb() {
+        let features = detect("PBxxyyrgb: Binary PX command without alpha\n");
+        assert_eq!(features.binary, Some(BinaryFormat::CoordLERGB));
+    }
+
+    #[test]
+    fn test_32_bit_coordinates() {
+        let features = detect("PBxxxxyyyyrgba: 32 bit little endian coordinates\n");
+        assert_eq!(features.binary, Some(BinaryFormat::Coord32LERGBA));
+    }
+
+    #[test]
+    fn test_binary_preference() {
+        let help = "PBxxxxyyyyrgba: 32 bit coordinates\nPBxxyyrgb: no alpha\nPBxyrgba: 16 bit\n";
+        assert_eq!(detect(help).binary, Some(BinaryFormat::CoordLERGBA));
+        // 16 bit coordinates can't address every pixel of this canvas
+        let features = detect_with_size("100000 1000", help);
+        assert_eq!(features.binary, Some(BinaryFormat::Coord32LERGBA));
+        let features = detect_with_size("100000 1000", "PBxyrgba: 16 bit\n");
+        assert_eq!(features.binary, None);
+    }
+
+    #[test]
+    fn test_bulk_format() {
+        let features = detect("PRxxyyllrgba...: a row of ll pixels, starting at x y\n");
+        assert_eq!(features.bulk, Some(BulkFormat::RowLERGBA));
+        assert_eq!(features.binary, None);
+        // 16 bit coordinates can't address every pixel of this canvas
+        let features = detect_with_size("100000 1000", "PRxxyyllrgba...: a row\n");
+        assert_eq!(features.bulk, None);
+    }
+}
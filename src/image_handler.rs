@@ -1,25 +1,82 @@
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use image::{DynamicImage, ImageError, ImageFormat, Rgba, RgbaImage};
+use bincode::{Decode, Encode};
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat, Rgba, RgbaImage};
 use log::{info, warn};
 use rand::rngs::SmallRng;
 use rand::{prelude::SliceRandom, SeedableRng};
 
 /// A single image, parsed into commands. Consists of multiple chunks of commands
 pub type Command = Vec<Vec<u8>>;
+/// A frame of an animation, and how long it is shown
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct TimedCommand {
+    pub command: Arc<Command>,
+    /// `None` if the source doesn't store it, the frame is then shown according to the frame rate
+    pub duration: Option<Duration>,
+}
+
+impl TimedCommand {
+    pub fn new(command: Command, duration: Option<Duration>) -> TimedCommand {
+        TimedCommand {
+            command: Arc::new(command),
+            duration,
+        }
+    }
+}
+
 /// A collection of image commands
-pub type CommandLib = Vec<Arc<Command>>;
+pub type CommandLib = Vec<TimedCommand>;
+
+/// If `command` doesn't paint a single pixel, like the delta between two equal frames
+pub fn is_idle(command: &Command) -> bool {
+    command.iter().all(Vec::is_empty)
+}
 
 pub use image::imageops::FilterType;
 use rand::rng;
 use crate::feature_detection::Features;
 
 /// Format for binary encoded images
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum BinaryFormat {
+    /// `PBxxyyrgba` with 2b little endian coordinates
+    #[cfg_attr(feature = "clap", value(name = "le-rgba"))]
     CoordLERGBA,
+    /// `PBxxyyrgba` with 2b big endian coordinates
+    #[cfg_attr(feature = "clap", value(name = "be-rgba"))]
+    CoordBERGBA,
+    /// `PBxxyyrgb` with 2b little endian coordinates and no alpha
+    #[cfg_attr(feature = "clap", value(name = "le-rgb"))]
+    CoordLERGB,
+    /// `PBxxxxyyyyrgba` with 4b little endian coordinates for very large canvases
+    #[cfg_attr(feature = "clap", value(name = "le32-rgba"))]
+    Coord32LERGBA,
+}
+
+impl BinaryFormat {
+    /// Size of a single pixel command in bytes
+    pub fn command_size(&self) -> usize {
+        match self {
+            BinaryFormat::CoordLERGBA | BinaryFormat::CoordBERGBA => 10,
+            BinaryFormat::CoordLERGB => 9,
+            BinaryFormat::Coord32LERGBA => 14,
+        }
+    }
+}
+
+/// Format for bulk commands, setting a whole run of pixels with a single command
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum BulkFormat {
+    /// `PRxxyyllrgba...` with 2b little endian coordinates and run length, followed by rgba for
+    /// each pixel of the horizontal run starting at x, y
+    #[cfg_attr(feature = "clap", value(name = "le-row-rgba"))]
+    RowLERGBA,
 }
 
 pub struct ImageConfigBuilder {
@@ -32,8 +89,10 @@ pub struct ImageConfigBuilder {
     alpha_usage: bool,
     shuffle: bool,
     binary: Option<BinaryFormat>,
+    bulk: Option<BulkFormat>,
     chunks: usize,
     resize: bool,
+    datagram_size: Option<usize>,
 }
 
 impl ImageConfigBuilder {
@@ -48,8 +107,10 @@ impl ImageConfigBuilder {
             alpha_usage: false,
             shuffle: true,
             binary: None,
+            bulk: None,
             chunks: 1,
             resize: false,
+            datagram_size: None,
         }
     }
 
@@ -107,6 +168,18 @@ impl ImageConfigBuilder {
         self
     }
 
+    /// Which binary command format should be used, if any
+    pub fn binary_format(mut self, binary: Option<BinaryFormat>) -> ImageConfigBuilder {
+        self.binary = binary;
+        self
+    }
+
+    /// Which bulk command format should be used, if any
+    pub fn bulk_format(mut self, bulk: Option<BulkFormat>) -> ImageConfigBuilder {
+        self.bulk = bulk;
+        self
+    }
+
     /// Shuffle draw commands (RECOMMENDED)
     pub fn shuffle(mut self, shuffle: bool) -> ImageConfigBuilder {
         self.shuffle = shuffle;
@@ -128,6 +201,14 @@ impl ImageConfigBuilder {
         self
     }
 
+    /// Largest chunk in bytes, chunks are split further between two commands to stay below it
+    /// NOTE: this is needed for UDP and WebSockets, where every chunk is sent as a single datagram
+    /// or message
+    pub fn datagram_size(mut self, datagram_size: Option<usize>) -> ImageConfigBuilder {
+        self.datagram_size = datagram_size;
+        self
+    }
+
     pub fn apply_features(mut self, features: Features) -> ImageConfigBuilder {
         self.width = Some(self.width.unwrap_or(features.width));
         self.height = Some(self.height.unwrap_or(features.height));
@@ -136,6 +217,9 @@ impl ImageConfigBuilder {
         if self.binary.is_none() {
             self.binary = features.binary;
         }
+        if self.bulk.is_none() {
+            self.bulk = features.bulk;
+        }
         self
     }
 
@@ -151,8 +235,10 @@ impl ImageConfigBuilder {
             alpha_usage: self.alpha_usage,
             shuffle: self.shuffle,
             binary: self.binary,
+            bulk: self.bulk,
             chunks: self.chunks,
             resize: self.resize,
+            datagram_size: self.datagram_size,
         }
     }
 }
@@ -164,13 +250,15 @@ impl Default for ImageConfigBuilder {
 }
 
 /// Configuration for how to place a picture, and what features to use
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Encode, Decode)]
 pub struct ImageConfig {
     /// Largest width of the image.
-    /// NOTE: this needs to be `canvas_width - x_offset` to crop at the canvas edges
+    /// NOTE: this needs to be `canvas_width - x_offset` to crop at the canvas edges, see
+    /// `crop_to_canvas`
     pub width: Option<u32>,
     /// Largest height of the image.
-    /// NOTE: this needs to be `canvas_height - y_offset` to crop at the canvas edges
+    /// NOTE: this needs to be `canvas_height - y_offset` to crop at the canvas edges, see
+    /// `crop_to_canvas`
     pub height: Option<u32>,
     /// At what x offset to place the image
     pub x_offset: u32,
@@ -190,6 +278,13 @@ pub struct ImageConfig {
     pub resize: bool,
     /// Use binary representation (Recommended if supported)
     pub binary: Option<BinaryFormat>,
+    /// Use bulk commands for runs of pixels (Recommended if supported, takes precedence over
+    /// binary representation)
+    pub bulk: Option<BulkFormat>,
+    /// Largest chunk in bytes, chunks are split further between two commands to stay below it
+    /// NOTE: this is needed for UDP and WebSockets, where every chunk is sent as a single datagram
+    /// or message
+    pub datagram_size: Option<usize>,
 }
 
 impl Default for ImageConfig {
@@ -198,12 +293,27 @@ impl Default for ImageConfig {
     }
 }
 
+impl ImageConfig {
+    /// Limit the size to the part of a `width` x `height` canvas below and right of the offset,
+    /// an unset size fills all of it
+    pub fn crop_to_canvas(mut self, (width, height): (u32, u32)) -> ImageConfig {
+        let max_width = width.saturating_sub(self.x_offset);
+        self.width = Some(self.width.unwrap_or(max_width).min(max_width));
+        let max_height = height.saturating_sub(self.y_offset);
+        self.height = Some(self.height.unwrap_or(max_height).min(max_height));
+        self
+    }
+}
+
 const CHUNK_SIZE: u32 = 10;
 // Longest command: PX xxxx yyyy rrggbbaa\n
 const NORMAL_SIZE: usize = 22;
 // Longest offset command: PX x y rrggbbaa\n
 // assumes chunk size of 10
 const OFFSET_SIZE: usize = 16;
+// Longest run of pixels in a single bulk command
+// Keeps the commands small enough to be evenly distributed across chunks
+const MAX_RUN: u32 = 64;
 
 #[inline(always)]
 fn id_for_chunk_x_y(x: u32, y: u32, chunk_width: u32) -> usize {
@@ -215,11 +325,16 @@ fn id_for_px(x: u32, y: u32, chunk_width: u32) -> usize {
     id_for_chunk_x_y(x / CHUNK_SIZE, y / CHUNK_SIZE, chunk_width)
 }
 
-pub(crate) fn image_to_commands(mut image: DynamicImage, config: ImageConfig) -> Command {
+/// Parse an image into pixel commands
+pub fn image_to_commands(image: DynamicImage, config: ImageConfig) -> Command {
+    rgba_to_commands(&prepare_image(image, config), config)
+}
+
+/// Crop or resize the image according to the config
+pub(crate) fn prepare_image(mut image: DynamicImage, config: ImageConfig) -> RgbaImage {
     if config.width.is_some() != config.height.is_some() {
         warn!("Warning: Only setting width or height doesn't crop the image!")
     }
-    let start = Instant::now();
     let cropped_image = if let (Some(width), Some(height)) = (config.width, config.height) {
         #[allow(clippy::if_same_then_else)]
         if width == image.width() && height == image.height() {
@@ -235,18 +350,27 @@ pub(crate) fn image_to_commands(mut image: DynamicImage, config: ImageConfig) ->
     } else {
         image
     };
-    let rgba_image = cropped_image.to_rgba8();
-    let (final_result, relevant_pixels) = if config.binary.is_some() {
-        get_binary_encoded(&rgba_image, config)
+    cropped_image.to_rgba8()
+}
+
+/// Encode an already prepared image, skipping all fully transparent pixels
+pub(crate) fn rgba_to_commands(rgba_image: &RgbaImage, config: ImageConfig) -> Command {
+    let start = Instant::now();
+    let (final_result, relevant_pixels) = if config.bulk.is_some() {
+        get_bulk_encoded(rgba_image, config)
+    } else if config.binary.is_some() {
+        get_binary_encoded(rgba_image, config)
     } else if config.offset_usage {
         // encoding as offset is significantly faster than a full encoding
         // This might result in a less optimized image for sparse images, but the odds are
         // relatively low
-        get_offset_encoded(&rgba_image, config)
+        get_offset_encoded(rgba_image, config)
     } else {
-        get_full_encoded(&rgba_image, config)
+        get_full_encoded(rgba_image, config)
     };
-    let optimizations = if config.binary.is_some() {
+    let optimizations = if config.bulk.is_some() {
+        "using bulk optimization"
+    } else if config.binary.is_some() {
         "using binary optimization"
     } else if config.gray_usage && config.offset_usage {
         "using both gray and offset optimizations"
@@ -264,12 +388,67 @@ pub(crate) fn image_to_commands(mut image: DynamicImage, config: ImageConfig) ->
         size,
         size as f32 / relevant_pixels as f32
     );
-    final_result
+    match config.datagram_size {
+        Some(datagram_size) => split_chunks(&final_result, datagram_size, &config),
+        None => final_result,
+    }
 }
 
-/// Load image(s) from paths, parsing them into ready to use command chains
-pub fn load(paths: Vec<&str>, config: ImageConfig) -> CommandLib {
-    let images: Vec<_> = paths
+/// Returns a copy of `current` where all pixels that didn't change since `previous` are
+/// transparent. Pixels that became transparent are not included, as they can't be erased
+pub(crate) fn delta_image(previous: &RgbaImage, current: &RgbaImage) -> RgbaImage {
+    let mut delta = current.clone();
+    if previous.dimensions() != current.dimensions() {
+        return delta;
+    }
+    for (pixel, previous) in delta.pixels_mut().zip(previous.pixels()) {
+        if pixel == previous {
+            pixel.0[3] = 0;
+        }
+    }
+    delta
+}
+
+/// Encodes consecutive frames of an animation into delta commands, which only contain the
+/// pixels that changed since the previous frame. Every `keyframe_interval` frames, a full frame
+/// is encoded, so painters alternate between the cheap deltas and repainting the whole image
+pub struct DeltaEncoder {
+    config: ImageConfig,
+    keyframe_interval: usize,
+    previous: Option<RgbaImage>,
+    frame: usize,
+}
+
+impl DeltaEncoder {
+    pub fn new(config: ImageConfig, keyframe_interval: usize) -> DeltaEncoder {
+        if keyframe_interval == 0 {
+            panic!("Keyframe interval has to be greater than 0")
+        }
+        DeltaEncoder {
+            config,
+            keyframe_interval,
+            previous: None,
+            frame: 0,
+        }
+    }
+
+    /// Encode the next frame, either as keyframe or as delta to the previous frame
+    pub fn encode(&mut self, image: DynamicImage) -> Command {
+        let current = prepare_image(image, self.config);
+        let command = match &self.previous {
+            Some(previous) if !self.frame.is_multiple_of(self.keyframe_interval) => {
+                rgba_to_commands(&delta_image(previous, &current), self.config)
+            }
+            _ => rgba_to_commands(&current, self.config),
+        };
+        self.previous = Some(current);
+        self.frame += 1;
+        command
+    }
+}
+
+fn open_images(paths: Vec<&str>) -> Vec<DynamicImage> {
+    paths
         .into_iter()
         .map(|path_str| {
             let path = Path::new(path_str);
@@ -281,10 +460,24 @@ pub fn load(paths: Vec<&str>, config: ImageConfig) -> CommandLib {
             }
             image::open(path).expect("coudn't load image")
         })
-        .collect();
-    images
+        .collect()
+}
+
+/// Load image(s) from paths, parsing them into ready to use command chains
+pub fn load(paths: Vec<&str>, config: ImageConfig) -> CommandLib {
+    open_images(paths)
         .into_iter()
-        .map(|image| Arc::new(image_to_commands(image, config)))
+        .map(|image| TimedCommand::new(image_to_commands(image, config), None))
+        .collect()
+}
+
+/// Load image(s) from paths as an animation of delta commands, with a full frame every
+/// `keyframe_interval` frames. The first frame is always a full frame, so the animation can loop
+pub fn load_delta(paths: Vec<&str>, config: ImageConfig, keyframe_interval: usize) -> CommandLib {
+    let mut encoder = DeltaEncoder::new(config, keyframe_interval);
+    open_images(paths)
+        .into_iter()
+        .map(|image| TimedCommand::new(encoder.encode(image), None))
         .collect()
 }
 
@@ -298,6 +491,119 @@ pub fn load_from_memory(
     Ok(image_to_commands(image, config))
 }
 
+/// Load an image from memory and parse it into pixel commands, only containing the pixels that
+/// changed compared to the previous image
+pub fn load_delta_from_memory(
+    previous: &[u8],
+    input: &[u8],
+    config: ImageConfig,
+    format: ImageFormat,
+) -> Result<Command, ImageError> {
+    let previous = image::load_from_memory_with_format(previous, format)?;
+    let image = image::load_from_memory_with_format(input, format)?;
+    Ok(delta_to_commands(previous, image, config))
+}
+
+/// Parse an image into pixel commands, only containing the pixels that changed compared to the
+/// previous image
+pub fn delta_to_commands(previous: DynamicImage, image: DynamicImage, config: ImageConfig) -> Command {
+    let previous = prepare_image(previous, config);
+    let image = prepare_image(image, config);
+    rgba_to_commands(&delta_image(&previous, &image), config)
+}
+
+/// Length of the pixel command at the start of `data`, encoded according to `config`
+fn command_len(data: &[u8], config: &ImageConfig) -> usize {
+    let len = if let (Some(BulkFormat::RowLERGBA), [_, _, _, _, _, _, l0, l1, ..]) =
+        (config.bulk, data)
+    {
+        8 + u16::from_le_bytes([*l0, *l1]) as usize * 4
+    } else if let Some(format) = config.binary {
+        format.command_size()
+    } else {
+        data.iter()
+            .position(|b| *b == b'\n')
+            .map_or(data.len(), |pos| pos + 1)
+    };
+    len.min(data.len())
+}
+
+/// Split a chunk of commands into batches of at most `max_size` bytes, only splitting between
+/// two pixel commands. Commands larger than `max_size` end up in a batch of their own
+/// NOTE: `OFFSET` commands are not repeated, so batches of offset encoded chunks depend on the
+/// previous ones
+pub fn packetize(chunk: &[u8], max_size: usize, config: &ImageConfig) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < chunk.len() {
+        let len = command_len(&chunk[pos..], config);
+        if pos + len - start > max_size && pos > start {
+            batches.push(start..pos);
+            start = pos;
+        }
+        pos += len;
+    }
+    if start < chunk.len() {
+        batches.push(start..chunk.len());
+    }
+    batches
+}
+
+/// Split every chunk of `command`, encoded according to `config`, into chunks of at most
+/// `max_size` bytes, see `packetize`
+pub fn split_chunks(command: &Command, max_size: usize, config: &ImageConfig) -> Command {
+    command
+        .iter()
+        .flat_map(|chunk| {
+            packetize(chunk, max_size, config)
+                .into_iter()
+                .map(|batch| chunk[batch].to_vec())
+        })
+        .collect()
+}
+
+/// Split the chunks of `command` into `parts` disjoint shares, so every part paints other pixels
+/// If there are fewer chunks than parts, chunks are handed out to multiple parts
+pub fn partition(command: &Command, parts: usize) -> Vec<Command> {
+    (0..parts).map(|part| share(command, part, parts)).collect()
+}
+
+/// Share `part` of `parts` of `command`, as returned by `partition`
+pub fn share(command: &Command, part: usize, parts: usize) -> Command {
+    if !command.is_empty() && command.len() < parts {
+        return vec![command[part % command.len()].clone()];
+    }
+    command.iter().skip(part).step_by(parts.max(1)).cloned().collect()
+}
+
+/// Split a prepared image into `parts` horizontal bands, each with a config placing it at the
+/// right position. If the image has fewer rows than parts, bands are handed out to multiple parts
+pub fn split_bands(
+    image: &RgbaImage,
+    config: ImageConfig,
+    parts: usize,
+) -> Vec<(RgbaImage, ImageConfig)> {
+    let bands = parts.min(image.height() as usize);
+    let bands: Vec<_> = (0..bands)
+        .map(|band| {
+            let start = (image.height() as usize * band / bands) as u32;
+            let end = (image.height() as usize * (band + 1) / bands) as u32;
+            let config = ImageConfig {
+                width: Some(image.width()),
+                height: Some(end - start),
+                y_offset: config.y_offset + start,
+                ..config
+            };
+            let band = image.view(0, start, image.width(), end - start).to_image();
+            (band, config)
+        })
+        .collect();
+    (0..parts)
+        .filter_map(|part| bands.get(part % bands.len().max(1)).cloned())
+        .collect()
+}
+
 fn shuffle_collect<T, F: Fn(&T) -> Option<&[u8]>>(
     mut input: Vec<T>,
     size_hint: usize,
@@ -337,6 +643,28 @@ fn binary_encode(format: &BinaryFormat, x: u32, y: u32, px: &Rgba<u8>) -> Vec<u8
                 b'P', b'B', x[0], x[1], y[0], y[1], px.0[0], px.0[1], px.0[2], px.0[3],
             ]
         }
+        BinaryFormat::CoordBERGBA => {
+            let x = (x as u16).to_be_bytes();
+            let y = (y as u16).to_be_bytes();
+            vec![
+                b'P', b'B', x[0], x[1], y[0], y[1], px.0[0], px.0[1], px.0[2], px.0[3],
+            ]
+        }
+        BinaryFormat::CoordLERGB => {
+            let x = (x as u16).to_le_bytes();
+            let y = (y as u16).to_le_bytes();
+            vec![
+                b'P', b'B', x[0], x[1], y[0], y[1], px.0[0], px.0[1], px.0[2],
+            ]
+        }
+        BinaryFormat::Coord32LERGBA => {
+            let x = x.to_le_bytes();
+            let y = y.to_le_bytes();
+            vec![
+                b'P', b'B', x[0], x[1], x[2], x[3], y[0], y[1], y[2], y[3], px.0[0], px.0[1],
+                px.0[2], px.0[3],
+            ]
+        }
     }
 }
 
@@ -356,7 +684,54 @@ fn get_binary_encoded(rgba_image: &RgbaImage, config: ImageConfig) -> (Command,
         let y_pos = y + config.y_offset;
         intermediate.push(binary_encode(&format, x_pos, y_pos, pixel));
     }
-    let result = shuffle_collect(intermediate, relevant_pixels * 10, config, |c| Some(c));
+    let size_hint = relevant_pixels * format.command_size();
+    let result = shuffle_collect(intermediate, size_hint, config, |c| Some(c));
+    (result, relevant_pixels)
+}
+
+fn bulk_encode(format: &BulkFormat, x: u32, y: u32, rgba: &[u8]) -> Vec<u8> {
+    match format {
+        BulkFormat::RowLERGBA => {
+            let x = (x as u16).to_le_bytes();
+            let y = (y as u16).to_le_bytes();
+            let length = ((rgba.len() / 4) as u16).to_le_bytes();
+            let mut result = Vec::with_capacity(8 + rgba.len());
+            result.extend_from_slice(&[b'P', b'R', x[0], x[1], y[0], y[1], length[0], length[1]]);
+            result.extend_from_slice(rgba);
+            result
+        }
+    }
+}
+
+fn get_bulk_encoded(rgba_image: &RgbaImage, config: ImageConfig) -> (Command, usize) {
+    let Some(format) = config.bulk else {
+        panic!("Bulk encode without bulk format")
+    };
+    let (width, height) = rgba_image.dimensions();
+    let raw = rgba_image.as_raw();
+    let mut intermediate = Vec::new();
+    let mut relevant_pixels = 0;
+    let mut size = 0;
+    for y in 0..height {
+        let mut x = 0;
+        while x < width {
+            if rgba_image.get_pixel(x, y).0[3] == 0 {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < width && x - start < MAX_RUN && rgba_image.get_pixel(x, y).0[3] != 0 {
+                x += 1;
+            }
+            let row_start = (y * width) as usize * 4;
+            let rgba = &raw[row_start + start as usize * 4..row_start + x as usize * 4];
+            let cmd = bulk_encode(&format, start + config.x_offset, y + config.y_offset, rgba);
+            relevant_pixels += (x - start) as usize;
+            size += cmd.len();
+            intermediate.push(cmd);
+        }
+    }
+    let result = shuffle_collect(intermediate, size, config, |c| Some(c));
     (result, relevant_pixels)
 }
 
@@ -567,6 +942,16 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_delta_image() {
+        let previous = RgbaImage::from_pixel(2, 1, Rgba([1, 2, 3, 255]));
+        let mut current = previous.clone();
+        current.put_pixel(1, 0, Rgba([4, 5, 6, 255]));
+        let delta = delta_image(&previous, &current);
+        assert_eq!(delta.get_pixel(0, 0).0[3], 0);
+        assert_eq!(delta.get_pixel(1, 0), &Rgba([4, 5, 6, 255]));
+    }
+
     #[test]
     fn test_network_encoder() {
         let x = 0x1234;
@@ -578,4 +963,130 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    fn test_bulk_encoder() {
+        let rgba = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        let expected = vec![
+            b'P', b'R', 0x34, 0x12, 0x76, 0x98, 0x02, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ];
+        assert_eq!(
+            bulk_encode(&BulkFormat::RowLERGBA, 0x1234, 0x9876, &rgba),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_packetize() {
+        let config = ImageConfig::default();
+        let chunk = b"PX 1 2 ffffff\nPX 3 4 000000\nPX 5 6 ff\n";
+        assert_eq!(packetize(chunk, 28, &config), vec![0..28, 28..38]);
+        assert_eq!(packetize(chunk, 10, &config), vec![0..14, 14..28, 28..38]);
+        let config = ImageConfigBuilder::new().binary_usage(true).build();
+        assert_eq!(packetize(&[0; 30], 25, &config), vec![0..20, 20..30]);
+    }
+
+    #[test]
+    fn test_datagram_size() {
+        let mut image = RgbaImage::new(20, 20);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Rgba([x as u8, y as u8, 0x0a, 255]);
+        }
+        let config = ImageConfigBuilder::new()
+            .binary_usage(true)
+            .chunks(2)
+            .datagram_size(Some(105))
+            .build();
+        let command = rgba_to_commands(&image, config);
+        assert!(command.len() > 2);
+        // every chunk holds whole commands, even though 0x0a is part of every command
+        assert!(command
+            .iter()
+            .all(|chunk| chunk.len() <= 105 && chunk.len() % 10 == 0 && chunk.starts_with(b"PB")));
+        let size: usize = command.iter().map(Vec::len).sum();
+        assert_eq!(size, 400 * 10);
+    }
+
+    #[test]
+    fn test_network_encoder_big_endian() {
+        let x = 0x1234;
+        let y = 0x9876;
+        let pixel = Rgba([0x01, 0x23, 0x45, 0x67]);
+        let expected = vec![b'P', b'B', 0x12, 0x34, 0x98, 0x76, 0x01, 0x23, 0x45, 0x67];
+        assert_eq!(
+            binary_encode(&BinaryFormat::CoordBERGBA, x, y, &pixel),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_network_encoder_rgb() {
+        let x = 0x1234;
+        let y = 0x9876;
+        let pixel = Rgba([0x01, 0x23, 0x45, 0x67]);
+        let expected = vec![b'P', b'B', 0x34, 0x12, 0x76, 0x98, 0x01, 0x23, 0x45];
+        assert_eq!(
+            binary_encode(&BinaryFormat::CoordLERGB, x, y, &pixel),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_network_encoder_32bit() {
+        let x = 0x12345678;
+        let y = 0x9876;
+        let pixel = Rgba([0x01, 0x23, 0x45, 0x67]);
+        let expected = vec![
+            b'P', b'B', 0x78, 0x56, 0x34, 0x12, 0x76, 0x98, 0x00, 0x00, 0x01, 0x23, 0x45, 0x67,
+        ];
+        assert_eq!(
+            binary_encode(&BinaryFormat::Coord32LERGBA, x, y, &pixel),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_partition() {
+        let command: Command = (0..5u8).map(|i| vec![i]).collect();
+        assert_eq!(
+            partition(&command, 2),
+            vec![vec![vec![0], vec![2], vec![4]], vec![vec![1], vec![3]]]
+        );
+        let command: Command = vec![vec![0], vec![1]];
+        assert_eq!(
+            partition(&command, 3),
+            vec![vec![vec![0]], vec![vec![1]], vec![vec![0]]]
+        );
+        assert!(partition(&command, 0).is_empty());
+    }
+
+    #[test]
+    fn test_crop_to_canvas() {
+        let config = ImageConfigBuilder::new().x_offset(100).y_offset(50).build();
+        let cropped = config.crop_to_canvas((1920, 1080));
+        assert_eq!((cropped.width, cropped.height), (Some(1820), Some(1030)));
+        let config = ImageConfigBuilder::new().width(200).height(2000).x_offset(100).build();
+        let cropped = config.crop_to_canvas((1920, 1080));
+        assert_eq!((cropped.width, cropped.height), (Some(200), Some(1080)));
+        // an offset outside of the canvas leaves nothing to paint
+        let config = ImageConfigBuilder::new().x_offset(2000).build();
+        assert_eq!(config.crop_to_canvas((1920, 1080)).width, Some(0));
+    }
+
+    #[test]
+    fn test_split_bands() {
+        let image = RgbaImage::from_fn(2, 4, |_, y| Rgba([y as u8, 0, 0, 255]));
+        let config = ImageConfigBuilder::new().y_offset(10).build();
+        let bands = split_bands(&image, config, 3);
+        let rows: Vec<_> = bands
+            .iter()
+            .map(|(band, config)| (band.height(), config.y_offset, config.height))
+            .collect();
+        assert_eq!(rows, vec![(1, 10, Some(1)), (1, 11, Some(1)), (2, 12, Some(2))]);
+        assert_eq!(bands[2].0.get_pixel(0, 0).0[0], 2);
+        let bands = split_bands(&image, config, 6);
+        assert_eq!(bands.len(), 6);
+        assert_eq!(bands[4].1.y_offset, bands[0].1.y_offset);
+    }
 }
@@ -1,9 +1,31 @@
+#[cfg(feature = "tokio")]
+mod async_client;
+pub mod cache;
 mod client;
+mod connection;
+#[cfg(feature = "animation")]
+pub mod decoder;
 pub mod feature_detection;
 pub mod image_handler;
 mod painter;
+pub mod payload;
+mod transport;
+#[cfg(target_os = "linux")]
+mod zero_copy;
 
 pub mod service;
+#[cfg(feature = "tls")]
+pub mod tls;
 
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncClient;
 pub use client::Client;
-pub use painter::painter;
+pub use connection::Connection;
+#[cfg(feature = "websocket")]
+pub use connection::WebSocketStream;
+#[cfg(feature = "tokio")]
+pub use painter::async_painter;
+pub use painter::{painter, CommandSink, Slot};
+pub use transport::Transport;
+#[cfg(target_os = "linux")]
+pub use zero_copy::ZeroCopyClient;
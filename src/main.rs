@@ -1,32 +1,37 @@
-use crate::manager::{load_from_video, manage_dynamic};
-use manager::manage;
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::manager::{manage, manage_dynamic, FileInput};
 use pixelbomber::{
-    feature_detection,
-    image_handler::{self, BinaryFormat},
-    service::{Host, Service, ServiceBuilder},
+    feature_detection::{self, Features},
+    payload::{Mismatch, Payload},
+    service::{stats::StatsSnapshot, Host, Protocol, Service, ServiceBuilder},
     Client,
 };
 
 mod arg_handler;
 mod camera;
+mod compile;
+mod control;
 mod manager;
+mod stream;
 
 fn main() {
     env_logger::init();
     let mut args = arg_handler::parse();
-    let mut image_config = image_handler::ImageConfig {
-        width: args.width,
-        height: args.height,
-        x_offset: args.x,
-        y_offset: args.y,
-        offset_usage: args.offset,
-        gray_usage: args.gray,
-        alpha_usage: args.alpha,
-        binary: None,
-        shuffle: !args.shuffle,
-        chunks: args.count.unwrap_or(4) as usize,
-        resize: args.resize,
-    };
+    if let Some(arg_handler::Command::Compile(compile_args)) = args.command.take() {
+        if let Err(err) = compile::compile(compile_args) {
+            println!("{err}");
+        }
+        return;
+    }
+    let host_arg = args.host.take().expect("The host is required without a subcommand");
+    let mut image_config = args
+        .encoding
+        .image_config(args.count.unwrap_or(4) as usize);
 
     if args.test_green_screen {
         camera::test_green_screen(&args.image[0]);
@@ -34,24 +39,33 @@ fn main() {
     }
 
     let mut host = Host::new(
-        &args.host,
+        &host_arg,
         if args.listen_manager {None} else {args.bind_addr.take()}
     ).unwrap();
-    if args.le_rgba {
-        image_config.binary = Some(BinaryFormat::CoordLERGBA)
+    if host.protocol == Protocol::Udp {
+        // UDP is write only, so there is no way to detect features
+        args.feature_detection = true;
+        // every datagram has to stand on its own
+        image_config.offset_usage = false;
+        if args.repair {
+            println!("--repair reads back the canvas, which is not possible over UDP");
+            return;
+        }
     }
+    // datagrams and WebSocket messages can't be split in the middle of a command
+    image_config.datagram_size = host.protocol.max_chunk_size();
+    let mut canvas = None;
+    let mut detected = None;
     if !args.feature_detection && !args.listen_manager {
-        let mut client = Client::new(host.new_stream().unwrap());
+        let mut client = Client::new(host.new_connection().unwrap());
         let features = feature_detection::feature_detection(&mut client).unwrap();
-        let max_width = features.width - args.x;
-        image_config.width = Some(image_config.width.unwrap_or(max_width).min(max_width));
-        let max_height = features.height - args.y;
-        image_config.height = Some(image_config.height.unwrap_or(max_height).min(max_height));
+        image_config = image_config.crop_to_canvas((features.width, features.height));
         image_config.offset_usage = image_config.offset_usage || features.offset;
         image_config.gray_usage = image_config.gray_usage || features.px_gray;
         if image_config.binary.is_none() {
             image_config.binary = features.binary;
         }
+        canvas = Some((features.width, features.height));
         println!("Canvas size: {} x {}", features.width, features.height);
         if features.px_gray {
             println!("PX x y gg command supported")
@@ -59,55 +73,231 @@ fn main() {
         if features.offset {
             println!("OFFSET command supported")
         }
-        if features.binary.is_some() {
-            println!("PBxyrgba command supported (binary pixel)")
+        if let Some(format) = features.binary {
+            println!("Binary pixel command supported ({format:?})")
         }
+        // formats given on the command line are trusted, they can't be detected
+        detected = Some(Features {
+            binary: image_config.binary,
+            bulk: image_config.bulk,
+            ..features
+        });
     }
     if args.image.is_empty() && !args.listen_manager {
         println!("Please specify at least one image path!");
         return;
     }
+    let mut payload = match args.image.as_slice() {
+        [path] if Payload::is_payload(Path::new(path)) => match Payload::read(Path::new(path)) {
+            Ok(payload) => Some(payload),
+            Err(err) => {
+                println!("Unable to load {path} ({err})");
+                return;
+            }
+        },
+        _ => None,
+    };
+    if let Some(payload) = &mut payload {
+        let mismatches = match &detected {
+            Some(features) => payload.check(features),
+            None => {
+                println!("Feature detection is disabled, the payload can't be checked against the server");
+                Vec::new()
+            }
+        };
+        for mismatch in &mismatches {
+            println!("Payload mismatch: {mismatch}");
+        }
+        if mismatches.iter().any(Mismatch::is_fatal) {
+            println!("The server can't paint this payload, compile it again with matching options");
+            return;
+        }
+        if host.protocol == Protocol::Udp && payload.config.offset_usage {
+            println!("Payloads using the OFFSET command can't be sent over UDP");
+            return;
+        }
+        if let Some(datagram_size) = image_config.datagram_size {
+            payload.fit_datagrams(datagram_size);
+        }
+        image_config = payload.config;
+    }
+    let file_input = FileInput {
+        video: args.encoding.video,
+        images: args.repair || args.manager_images,
+        workers: args.encoding.workers as usize,
+        keyframe_interval: args.encoding.keyframe_interval.map(|interval| interval as usize),
+        stream: args.stream.map(|buffer| buffer as usize),
+        cache: args.cache.clone(),
+    };
+    let mut reloadable = false;
+    let mut prepared = false;
     let mut converter_threads = 0;
     let mut threads = args.count.unwrap_or(10) as usize;
-    let mut closure: Box<dyn FnMut(&mut Service)> =
+    let mut closure: control::Source =
         if args.image.len() == 1 && (&args.image[0] == "-" || args.image[0] == "/dev/stdin") {
-            converter_threads = args.workers;
+            converter_threads = args.encoding.workers;
             Box::new(manage_dynamic(args.continuous))
         } else if args.image.len() == 1 && args.image[0].starts_with("/dev/video") {
-            converter_threads = args.workers;
+            converter_threads = args.encoding.workers;
             Box::new(camera::get_callback(
                 &args.image[0],
                 args.green_screen.clone(),
             ))
-        } else if args.video {
-            if !args.image.len() == 1 {
-                println!("--video only works with exactly one input file");
-                return;
-            }
-            let Some(images) = load_from_video(&args.image[0], image_config, args.workers as usize)
-            else {
-                return;
+        } else if args.listen_manager {
+            let credentials = pixelbomber::service::moderator::Credentials {
+                secret: args.manager_secret.clone().unwrap_or_default().into_bytes(),
+                #[cfg(feature = "tls")]
+                pin: args.manager_pin,
             };
-            Box::new(manage(images, args.fps))
-        }else if args.listen_manager {
-            let server = pixelbomber::service::moderator::Client::new(host, args.bind_addr).unwrap();
+            let server = pixelbomber::service::moderator::Client::with_credentials(
+                host,
+                args.bind_addr,
+                credentials,
+            ).unwrap();
             host = server.target_host.clone();
             threads = server.threads;
+            // the manager might send images instead of commands
+            converter_threads = args.encoding.workers;
             Box::new(server.start())
+        } else if let Some(payload) = payload {
+            prepared = true;
+            Box::new(manage(payload.library, args.encoding.fps))
         } else {
-            let paths = args.image.iter().map(|v| v.as_str()).collect();
-            let command_lib = image_handler::load(paths, image_config);
-            Box::new(manage(command_lib, args.fps))
+            reloadable = true;
+            match file_input.load(&args.image, image_config, args.encoding.fps) {
+                Ok(source) => source,
+                Err(err) => {
+                    println!("{err}");
+                    return;
+                }
+            }
+        };
+    if let Some(port) = args.control {
+        let requests = match control::listen(port) {
+            Ok(requests) => requests,
+            Err(err) => {
+                println!("Unable to listen for control connections ({err})");
+                return;
+            }
+        };
+        let settings = control::Settings {
+            paths: args.image.clone(),
+            config: image_config,
+            fps: args.encoding.fps,
+            size: (args.encoding.width, args.encoding.height),
+            canvas,
         };
+        let mut controller = control::Controller::new(requests, closure, settings);
+        if reloadable {
+            controller = controller.loader(Box::new(move |paths, config, fps| {
+                file_input.load(paths, config, fps)
+            }));
+        }
+        if prepared {
+            controller = controller.prepared();
+        }
+        closure = Box::new(move |service: &mut Service| controller.run(service));
+    }
+    if args.detect_limit {
+        let mut source = closure;
+        let mut reported = None;
+        let adapt_threads = args.adapt_threads;
+        closure = Box::new(move |service: &mut Service| {
+            if let Some(limit) = service.connection_limit() {
+                if reported != Some(limit) {
+                    reported = Some(limit);
+                    println!("Server accepts {limit} connection(s)");
+                    if adapt_threads {
+                        match service.set_threads(limit) {
+                            Ok(()) => println!("Reduced to {limit} painter(s)"),
+                            Err(err) => println!("Unable to reduce the number of painters ({err})"),
+                        }
+                    }
+                }
+            }
+            source(service)
+        });
+    }
+    if let (Some(secs), None) = (args.stats, args.serve_manager) {
+        let mut source = closure;
+        let mut print = print_stats(Duration::from_secs(secs));
+        closure = Box::new(move |service: &mut Service| {
+            print(service);
+            source(service)
+        });
+    }
     let mut service = ServiceBuilder::new(host)
         .channel_limit(10)
         .converter_threads(converter_threads as usize)
         .image_config(image_config)
-        .threads(threads);
+        .threads(threads)
+        .zero_copy(args.zero_copy)
+        .repair(args.repair)
+        .manager_images(args.manager_images);
     if let Some(port) = args.serve_manager {
         service = service.listen_port(port);
     }
+    if let Some(secret) = args.manager_secret {
+        service = service.manager_secret(secret);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (&args.manager_cert, &args.manager_key) {
+        let config = pixelbomber::tls::server_config(cert, key).unwrap();
+        let pin = pixelbomber::tls::certificate_pin(cert).unwrap();
+        println!("Manager certificate pin: {}", pixelbomber::tls::pin_to_hex(&pin));
+        service = service.manager_tls(config);
+    }
+    #[cfg(feature = "tokio")]
+    if let Some(worker_threads) = args.async_runtime {
+        service = service.async_runtime(worker_threads);
+    }
     let mut service = service.build();
+    if args.serve_manager.is_some() {
+        let status = service.manager_status();
+        let interval = Duration::from_secs(args.stats.unwrap_or(10));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            println!("{status}");
+        });
+    }
     service.loop_callback(closure.as_mut());
     service.stop();
 }
+
+/// Print the throughput of all painters every `interval`
+fn print_stats(interval: Duration) -> impl FnMut(&Service) {
+    let mut last_print = Instant::now();
+    let mut previous: Vec<StatsSnapshot> = Vec::new();
+    move |service: &Service| {
+        if last_print.elapsed() < interval {
+            return;
+        }
+        let seconds = last_print.elapsed().as_secs_f64();
+        last_print = Instant::now();
+        let painters = service.painter_stats();
+        let (mut bytes, mut frames) = (0, 0);
+        for (i, now) in painters.iter().enumerate() {
+            // painters that were added since the last print start at zero
+            let before = previous.get(i).copied().unwrap_or_default();
+            let painter_bytes = now.bytes.saturating_sub(before.bytes);
+            let painter_frames = now.frames.saturating_sub(before.frames);
+            log::info!(
+                "Painter {i}: {:.2} MiB/s, {:.1} frames/s, {} connection(s), {} reconnect(s), {} error(s)",
+                painter_bytes as f64 / seconds / (1024.0 * 1024.0),
+                painter_frames as f64 / seconds,
+                now.connections,
+                now.reconnects,
+                now.errors,
+            );
+            bytes += painter_bytes;
+            frames += painter_frames;
+        }
+        println!(
+            "{}, {:.2} MiB/s, {:.1} frames/s",
+            service.stats(),
+            bytes as f64 / seconds / (1024.0 * 1024.0),
+            frames as f64 / seconds,
+        );
+        previous = painters;
+    }
+}
@@ -1,30 +1,272 @@
-use image::ImageFormat;
-use pixelbomber::service::Service;
+use image::{DynamicImage, ImageFormat};
+use pixelbomber::{
+    cache::{self, Cache, CacheKey},
+    decoder,
+    service::Service,
+};
 use std::collections::HashMap;
-use std::io::Read;
-use std::process::Stdio;
-use std::sync::mpsc::sync_channel;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, Stdio};
+use std::sync::mpsc::{channel, sync_channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::{
     io,
-    thread::{self, sleep},
-    time::Duration,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
 };
 use sysinfo::System;
 
-use pixelbomber::image_handler::{load_from_memory, CommandLib, ImageConfig};
+use pixelbomber::image_handler::{
+    delta_to_commands, image_to_commands, load, load_delta, load_delta_from_memory,
+    load_from_memory, Command, CommandLib, ImageConfig, TimedCommand,
+};
+
+use crate::control::Source;
+use crate::stream;
+
+/// How long a single image waits between calls, it only has to be sent once
+pub const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+/// Playback that falls behind further than this continues from now instead of catching up
+const MAX_LAG: Duration = Duration::from_secs(1);
+
+/// Schedules frames on a monotonic clock, so the time spent sending a frame doesn't add up
+#[derive(Default)]
+pub struct Schedule {
+    next: Option<Instant>,
+}
+
+impl Schedule {
+    /// Wait until the current frame was shown for `duration`
+    pub fn wait(&mut self, duration: Duration) {
+        let now = Instant::now();
+        let mut next = self.next.unwrap_or(now) + duration;
+        if let Some(remaining) = next.checked_duration_since(now) {
+            sleep(remaining);
+        } else if now - next > MAX_LAG {
+            next = now;
+        }
+        self.next = Some(next);
+    }
+}
 
-pub fn manage(commands: CommandLib, mut fps: f32) -> impl FnMut(&mut Service) {
+/// How long frames without a duration are shown at `fps`
+pub fn frame_interval(fps: f32) -> Duration {
+    // the frame rate is validated, this only keeps a broken one from panicking
+    Duration::try_from_secs_f32(1.0 / fps).unwrap_or(IDLE_INTERVAL)
+}
+
+/// Show each frame for its duration, or at `fps` if it has none
+pub fn manage(commands: CommandLib, fps: f32) -> impl FnMut(&mut Service) {
     let mut frame = 0;
-    if commands.len() == 1 {
-        fps = 0.000001;
+    let frames = commands.len();
+    let durations: Vec<Option<Duration>> = commands.iter().map(|command| command.duration).collect();
+    let mut commands = Some(commands);
+    let mut schedule = Schedule::default();
+    move |service: &mut Service| {
+        // managers send the whole animation to their clients only once
+        let first = commands.is_some();
+        if let Some(commands) = commands.take() {
+            service.preload(commands);
+        }
+        if frames == 1 {
+            if first {
+                service.show_frame(0);
+            } else {
+                sleep(IDLE_INTERVAL);
+            }
+            return;
+        }
+        service.show_frame(frame);
+        schedule.wait(durations[frame].unwrap_or_else(|| frame_interval(fps)));
+        frame = (frame + 1) % frames;
     }
+}
+
+/// Send each image for its duration, or at `fps` if it has none
+pub fn manage_images(
+    images: Vec<(DynamicImage, Option<Duration>)>,
+    fps: f32,
+) -> impl FnMut(&mut Service) {
+    let mut frame = 0;
+    let mut first = true;
+    let mut schedule = Schedule::default();
     move |service: &mut Service| {
-        service.send_command(commands[frame].clone());
-        frame = (frame + 1) % commands.len();
+        if images.len() == 1 {
+            if first {
+                service.send_image(images[0].0.clone());
+                first = false;
+            } else {
+                sleep(IDLE_INTERVAL);
+            }
+            return;
+        }
+        let (image, duration) = &images[frame];
+        service.send_image(image.clone());
+        schedule.wait(duration.unwrap_or_else(|| frame_interval(fps)));
+        frame = (frame + 1) % images.len();
+    }
+}
+
+/// How image files from the command line are turned into frames
+#[derive(Clone)]
+pub struct FileInput {
+    /// The only file is a video, decoded with ffmpeg unless it can be decoded in process
+    pub video: bool,
+    /// Send images instead of pixel commands
+    pub images: bool,
+    pub workers: usize,
+    pub keyframe_interval: Option<usize>,
+    /// Decode while playing, with at most this many frames buffered
+    pub stream: Option<usize>,
+    /// Directory to keep encoded frames in for the next start
+    pub cache: Option<PathBuf>,
+}
+
+impl FileInput {
+    /// Load the files at `paths` and play them at `fps`, animations and videos that store the
+    /// durations of their frames are played with those instead
+    pub fn load(&self, paths: &[String], config: ImageConfig, fps: f32) -> Result<Source, String> {
+        if let Some(buffer) = self.stream {
+            return stream::load(self, paths, config, fps, buffer);
+        }
+        // videos decoded by ffmpeg are always sent as commands
+        if self.images && !self.video {
+            return Ok(Box::new(manage_images(load_images(paths)?, fps)));
+        }
+        let cached = self.cached(paths, config);
+        if let Some((cache, key)) = &cached {
+            if let Some(library) = cache.load(key) {
+                println!("Loaded {} frame(s) from the cache", library.len());
+                return Ok(Box::new(manage(library, fps)));
+            }
+        }
+        let library = self.encode(paths, config)?;
+        if let Some((cache, key)) = &cached {
+            if let Err(err) = cache.store(key, &library) {
+                println!("Unable to write the cache ({err})");
+            }
+        }
+        Ok(Box::new(manage(library, fps)))
+    }
+
+    /// Encode the files at `paths` into pixel commands
+    pub fn encode(&self, paths: &[String], config: ImageConfig) -> Result<CommandLib, String> {
+        if let [path] = paths {
+            if let Some(frames) = open_animation(path)? {
+                return self.encode_animation(path, frames, config);
+            }
+        }
+        if self.video {
+            if paths.len() != 1 {
+                return Err("--video only works with exactly one input file".to_string());
+            }
+            return load_from_video(&paths[0], config, self.workers, self.keyframe_interval)
+                .ok_or_else(|| format!("Unable to load video {}", paths[0]));
+        }
+        if paths.is_empty() {
+            return Err("Please specify at least one image path!".to_string());
+        }
+        for path in paths {
+            image::image_dimensions(path)
+                .map_err(|err| format!("Unable to load {path} ({err})"))?;
+        }
+        let paths = paths.iter().map(String::as_str).collect();
+        Ok(match self.keyframe_interval {
+            Some(keyframe_interval) => load_delta(paths, config, keyframe_interval),
+            None => load(paths, config),
+        })
+    }
+
+    /// Where frames encoded from `paths` with `config` are cached, if they are
+    fn cached(&self, paths: &[String], config: ImageConfig) -> Option<(Cache, CacheKey)> {
+        let dir = self.cache.as_ref()?;
+        // only files can be hashed, not urls or devices
+        let source = cache::hash_sources(paths).ok()?;
+        let key = CacheKey {
+            source,
+            config,
+            keyframe_interval: self.keyframe_interval,
+        };
+        Some((Cache::new(dir), key))
+    }
+
+    /// Encode the frames of an animation on `workers` threads
+    fn encode_animation(
+        &self,
+        path: &str,
+        frames: decoder::Frames,
+        config: ImageConfig,
+    ) -> Result<CommandLib, String> {
+        let mut delays = Vec::new();
+        let mut error = None;
+        let images = frames.map_while(|frame| match frame {
+            Ok(frame) => {
+                delays.push(frame.delay);
+                Some(frame.image)
+            }
+            Err(err) => {
+                error = Some(err);
+                None
+            }
+        });
+        let commands = encode_frames(
+            images,
+            self.workers,
+            self.keyframe_interval,
+            move |image, previous| {
+                Some(match previous {
+                    Some(previous) => delta_to_commands(previous, image, config),
+                    None => image_to_commands(image, config),
+                })
+            },
+        );
+        if let Some(err) = error {
+            return Err(format!("Unable to load {path} ({err})"));
+        }
+        if commands.is_empty() {
+            return Err(format!("{path} contains no frames"));
+        }
+        Ok(commands
+            .into_iter()
+            .map(|(frame, command)| TimedCommand::new(command, Some(delays[frame])))
+            .collect())
+    }
+}
 
-        sleep(Duration::from_secs_f32(1.0 / fps))
+/// Decode an animated image or a Y4M video in process, returns `None` for other files
+fn open_animation(path: &str) -> Result<Option<decoder::Frames>, String> {
+    // ffmpeg also reads from urls and devices, which can't be decoded in process
+    if !Path::new(path).is_file() {
+        return Ok(None);
     }
+    decoder::open(Path::new(path)).map_err(|err| format!("Unable to load {path} ({err})"))
+}
+
+/// Load images to send instead of pixel commands, with the durations of animations
+fn load_images(paths: &[String]) -> Result<Vec<(DynamicImage, Option<Duration>)>, String> {
+    if let [path] = paths {
+        if let Some(frames) = open_animation(path)? {
+            return frames
+                .map(|frame| {
+                    frame
+                        .map(|frame| (frame.image, Some(frame.delay)))
+                        .map_err(|err| format!("Unable to load {path} ({err})"))
+                })
+                .collect();
+        }
+    }
+    if paths.is_empty() {
+        return Err("Please specify at least one image path!".to_string());
+    }
+    paths
+        .iter()
+        .map(|path| {
+            image::open(path)
+                .map(|image| (image, None))
+                .map_err(|err| format!("Unable to load {path} ({err})"))
+        })
+        .collect()
 }
 
 pub fn manage_dynamic(continuous: bool) -> impl FnMut(&mut Service) {
@@ -37,12 +279,74 @@ pub fn manage_dynamic(continuous: bool) -> impl FnMut(&mut Service) {
     }
 }
 
-pub fn load_from_video(path: &str, config: ImageConfig, workers: usize) -> Option<CommandLib> {
-    let mut cmd = std::process::Command::new("ffmpeg")
-        .arg("-v")
-        .arg("error")
+pub fn load_from_video(
+    path: &str,
+    config: ImageConfig,
+    workers: usize,
+    keyframe_interval: Option<usize>,
+) -> Option<CommandLib> {
+    let mut cmd = spawn_ffmpeg(path).expect("Unable to execute ffmpeg");
+    let (timestamps, timestamp_output) = channel();
+    let log = read_ffmpeg_log(cmd.stderr.take().unwrap(), timestamps);
+    let reader = BitmapReader::new(cmd.stdout.take().unwrap());
+    let result = encode_frames(
+        reader,
+        workers,
+        keyframe_interval,
+        move |image, previous| encode_bmp(image, previous, config),
+    );
+    // ffmpeg is still running if we ran out of memory
+    let _ = cmd.kill();
+    _ = cmd.wait();
+    let error = log.join().expect("Unable to read the log of ffmpeg");
+    if result.is_empty() {
+        print!("ffmpeg error:\n{}", error);
+        return None;
+    }
+    let timestamps: HashMap<u64, f64> = timestamp_output.try_iter().collect();
+    let timestamps: Vec<_> = (0..result.len() as u64)
+        .map(|frame| timestamps.get(&frame).copied())
+        .collect();
+    let durations = frame_durations(&timestamps);
+    Some(
+        result
+            .into_iter()
+            .map(|(frame, command)| {
+                TimedCommand::new(command, durations.get(frame).copied().flatten())
+            })
+            .collect(),
+    )
+}
+
+/// Encode a BMP frame of ffmpeg, as delta to `previous` if given. `None` if it can't be decoded
+pub fn encode_bmp(image: Vec<u8>, previous: Option<Vec<u8>>, config: ImageConfig) -> Option<Command> {
+    if let Some(previous) = previous {
+        let delta = load_delta_from_memory(&previous, &image, config, ImageFormat::Bmp);
+        if delta.is_ok() {
+            return delta.ok();
+        }
+        // the previous frame couldn't be decoded and was never painted, so this one is painted
+        // in full like a keyframe
+    }
+    load_from_memory(&image, config, ImageFormat::Bmp).ok()
+}
+
+/// Start ffmpeg, writing the frames of the video at `path` as BMP images to stdout
+pub fn spawn_ffmpeg(path: &str) -> io::Result<Child> {
+    std::process::Command::new("ffmpeg")
+        .arg("-hide_banner")
+        // the log level is needed to tell errors apart from the output of showinfo
+        .arg("-loglevel")
+        .arg("level+info")
+        .arg("-nostats")
         .arg("-i")
         .arg(path)
+        // showinfo logs the timestamp of every frame, passthrough keeps ffmpeg from duplicating
+        // or dropping frames to reach a constant frame rate
+        .arg("-vf")
+        .arg("showinfo")
+        .arg("-fps_mode")
+        .arg("passthrough")
         .arg("-f")
         .arg("image2pipe")
         .arg("-c")
@@ -51,18 +355,77 @@ pub fn load_from_video(path: &str, config: ImageConfig, workers: usize) -> Optio
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
-        .expect("Unable to execute ffmpeg");
-    let reader = BitmapReader::new(cmd.stdout.take().unwrap());
+}
+
+/// Read the log of ffmpeg in the background, so it can't fill the pipe. Sends the frame numbers
+/// and timestamps logged by the showinfo filter, and returns all errors
+pub fn read_ffmpeg_log(stderr: ChildStderr, timestamps: Sender<(u64, f64)>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut error = String::new();
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if line.contains("[error]") || line.contains("[fatal]") {
+                error.push_str(&line);
+                error.push('\n');
+                continue;
+            }
+            if let Some(timestamp) = parse_showinfo(&line) {
+                let _ = timestamps.send(timestamp);
+            }
+        }
+        error
+    })
+}
+
+/// The number and timestamp in seconds of a frame, from a line logged by the showinfo filter
+fn parse_showinfo(line: &str) -> Option<(u64, f64)> {
+    if !line.contains("Parsed_showinfo") {
+        return None;
+    }
+    let number = line.split_once(" n:")?.1.split_whitespace().next()?;
+    let timestamp = line.split_once("pts_time:")?.1.split_whitespace().next()?;
+    Some((number.parse().ok()?, timestamp.parse().ok()?))
+}
+
+/// Duration of a frame between two timestamps in seconds
+pub fn duration_between(start: f64, end: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(end - start)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+}
+
+/// Durations of frames with the given timestamps in seconds, the last frame is shown as long as
+/// the one before it. Frames next to a missing timestamp have no duration
+fn frame_durations(timestamps: &[Option<f64>]) -> Vec<Option<Duration>> {
+    let mut durations: Vec<_> = timestamps
+        .windows(2)
+        .map(|pair| pair[0].zip(pair[1]).and_then(|(start, end)| duration_between(start, end)))
+        .collect();
+    if let Some(last) = durations.last().copied() {
+        durations.push(last);
+    }
+    durations
+}
+
+/// Encode frames on `workers` threads, and return them in order together with their index.
+/// Every frame that isn't a keyframe is encoded together with the previous one. Stops early when
+/// less than 1GB of memory is left
+fn encode_frames<T: Clone + Send + 'static>(
+    frames: impl Iterator<Item = T>,
+    workers: usize,
+    keyframe_interval: Option<usize>,
+    encode: impl Fn(T, Option<T>) -> Option<Command> + Clone + Send + 'static,
+) -> Vec<(usize, Command)> {
     let mut worker_txs = Vec::with_capacity(workers);
     let mut handles = Vec::with_capacity(workers);
     let result_map = Arc::new(Mutex::new(HashMap::new()));
     for _ in 0..workers {
         let result_clone = result_map.clone();
-        let (worker_tx, worker_rx) = sync_channel::<(Vec<u8>, usize)>(1);
+        let encode = encode.clone();
+        let (worker_tx, worker_rx) = sync_channel::<(T, Option<T>, usize)>(1);
         worker_txs.push(worker_tx);
         handles.push(thread::spawn(move || {
-            while let Ok((image, frame)) = worker_rx.recv() {
-                let Ok(image) = load_from_memory(&image, config, ImageFormat::Bmp) else {
+            while let Ok((image, previous, frame)) = worker_rx.recv() {
+                let Some(image) = encode(image, previous) else {
                     continue;
                 };
                 {
@@ -72,43 +435,33 @@ pub fn load_from_video(path: &str, config: ImageConfig, workers: usize) -> Optio
             }
         }))
     }
-    let mut frame = 0;
+    let mut frame: usize = 0;
+    let mut previous: Option<T> = None;
     let mut system = System::new();
-    for bmp_data in reader {
+    for image in frames {
         system.refresh_memory();
         if system.available_memory() < 1_000_000_000 {
             println!("WARNING: Less than 1GB memory, stopping at frame {frame}");
-            let _ = cmd.kill();
             break;
         }
+        let is_keyframe = keyframe_interval.is_none_or(|interval| frame.is_multiple_of(interval));
+        let delta_base = if is_keyframe { None } else { previous.take() };
+        if keyframe_interval.is_some() {
+            previous = Some(image.clone());
+        }
         worker_txs[frame % workers]
-            .send((bmp_data, frame))
+            .send((image, delta_base, frame))
             .expect("Worker thread stopped working");
         frame += 1;
     }
-    _ = cmd.wait();
     drop(worker_txs);
     for worker in handles {
         let _ = worker.join();
     }
-    if frame == 0 {
-        let mut error = String::new();
-        cmd.stderr
-            .unwrap()
-            .read_to_string(&mut error)
-            .expect("Unable to read error message");
-        print!("ffmpeg error:\n{}", error);
-        return None;
-    }
-    let mut result = Vec::with_capacity(frame);
     let mut result_map = result_map.lock().expect("Unable to lock result_map");
-    let largest_key = result_map.keys().max().expect("No frames in results");
-    for frame in 0..*largest_key {
-        if let Some(entry) = result_map.remove(&frame) {
-            result.push(Arc::new(entry))
-        }
-    }
-    Some(result)
+    (0..frame)
+        .filter_map(|frame| Some((frame, result_map.remove(&frame)?)))
+        .collect()
 }
 
 pub struct ContinuousReader {
@@ -203,3 +556,69 @@ impl<R: Read> Iterator for BitmapReader<R> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_between() {
+        assert_eq!(duration_between(1.0, 1.5), Some(Duration::from_millis(500)));
+        // equal and reordered timestamps don't give a duration
+        assert_eq!(duration_between(1.0, 1.0), None);
+        assert_eq!(duration_between(1.5, 1.0), None);
+    }
+
+    #[test]
+    fn test_frame_durations() {
+        assert!(frame_durations(&[]).is_empty());
+        assert_eq!(frame_durations(&[Some(0.0)]), vec![]);
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(frame_durations(&[Some(0.0), Some(0.04)]), vec![ms(40), ms(40)]);
+        assert_eq!(
+            frame_durations(&[Some(0.0), Some(0.04), Some(0.04), Some(0.1)]),
+            vec![ms(40), None, ms(60), ms(60)]
+        );
+        // a missing timestamp only affects the frames next to it
+        assert_eq!(
+            frame_durations(&[Some(0.0), Some(0.04), None, Some(0.1), Some(0.14)]),
+            vec![ms(40), None, None, ms(40), ms(40)]
+        );
+    }
+
+    #[test]
+    fn test_parse_showinfo() {
+        let line = "[Parsed_showinfo_0 @ 0x5581] [info] n:   3 pts:  12012 pts_time:0.400400 \
+                    duration:   1001 duration_time:0.0333667 fmt:yuv420p";
+        assert_eq!(parse_showinfo(line), Some((3, 0.4004)));
+        let line = "[Parsed_showinfo_0 @ 0x5581] [info] config in time_base: 1/30000";
+        assert_eq!(parse_showinfo(line), None);
+        let line = "[info] n:   3 pts:  12012 pts_time:0.400400";
+        assert_eq!(parse_showinfo(line), None);
+    }
+
+    #[test]
+    fn test_encode_bmp_after_broken_frame() {
+        let mut bmp = io::Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut bmp, ImageFormat::Bmp)
+            .unwrap();
+        let bmp = bmp.into_inner();
+        let config = ImageConfig::default();
+        let keyframe = encode_bmp(bmp.clone(), None, config).unwrap();
+        assert!(!keyframe.iter().all(Vec::is_empty));
+        // nothing changed since the previous frame
+        let delta = encode_bmp(bmp.clone(), Some(bmp.clone()), config).unwrap();
+        assert!(delta.iter().all(Vec::is_empty));
+        // the previous frame was never painted, so the frame is painted in full
+        let full = encode_bmp(bmp.clone(), Some(b"broken".to_vec()), config).unwrap();
+        assert_eq!(full.concat().len(), keyframe.concat().len());
+        assert!(encode_bmp(b"broken".to_vec(), None, config).is_none());
+    }
+
+    #[test]
+    fn test_frame_interval() {
+        assert_eq!(frame_interval(4.0), Duration::from_millis(250));
+        assert_eq!(frame_interval(0.0), IDLE_INTERVAL);
+    }
+}
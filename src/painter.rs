@@ -1,35 +1,118 @@
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::Arc;
 
+#[cfg(feature = "tokio")]
+use tokio::sync::watch;
+
+#[cfg(feature = "tokio")]
+use crate::async_client::AsyncClient;
+#[cfg(feature = "tokio")]
+use crate::service::stats::Stats;
 use crate::client::Client;
-use crate::image_handler::Command;
+use crate::image_handler::{is_idle, Command};
+use crate::Transport;
+
+/// A connection the painter can write chunks of commands to
+pub trait CommandSink {
+    /// Send the chunk with index `chunk` of `commands`
+    fn send_chunk(&mut self, commands: &Arc<Command>, chunk: usize) -> Result<()>;
+
+    /// Called once all chunks of a painter were sent, before it starts over
+    fn frame_done(&mut self) {}
+}
+
+impl<T: Transport> CommandSink for Client<T> {
+    #[inline(always)]
+    fn send_chunk(&mut self, commands: &Arc<Command>, chunk: usize) -> Result<()> {
+        self.send_pixel(&commands[chunk])
+    }
+}
+
+/// Position `id` of a painter among `count` painters, decides which chunks the painter paints
+/// first. It can be moved while the painter is running, and is applied with the next command
+#[derive(Debug)]
+pub struct Slot(AtomicU64);
+
+impl Slot {
+    pub fn new(id: usize, count: usize) -> Slot {
+        Slot(AtomicU64::new(Self::pack(id, count)))
+    }
+
+    fn pack(id: usize, count: usize) -> u64 {
+        ((id as u64) << 32) | count as u64 & 0xffff_ffff
+    }
+
+    pub fn set(&self, id: usize, count: usize) {
+        self.0.store(Self::pack(id, count), Ordering::Relaxed);
+    }
+
+    /// The id and the number of painters
+    pub fn get(&self) -> (usize, usize) {
+        let value = self.0.load(Ordering::Relaxed);
+        ((value >> 32) as usize, (value & 0xffff_ffff) as usize)
+    }
+
+    /// Number of chunks to cycle through and the first chunk, for `commands`
+    fn start(&self, commands: &Command) -> (usize, usize) {
+        let (id, count) = self.get();
+        // commands without chunks are never painted, see `is_idle`
+        let max_idx = commands.len().max(1);
+        (max_idx, first_chunk(id, count, max_idx))
+    }
+}
+
+/// The chunk painter `id` of `count` painters starts with. Every painter paints all chunks, but
+/// they start spread evenly across them
+fn first_chunk(id: usize, count: usize, chunks: usize) -> usize {
+    if chunks <= count {
+        id % chunks
+    } else {
+        id * chunks / count
+    }
+}
 
 /// Paint an image to the canvas, can receive image ids to change between frames of an animation
-pub fn painter(
+pub fn painter<S: CommandSink>(
     rx: &Receiver<Arc<Command>>,
-    mut client: Client,
-    painter_id: usize,
-    max_frame: usize,
+    mut client: S,
+    slot: &Slot,
     mut current_commands: Arc<Command>,
 ) -> Arc<Command> {
     // Waits for first frame
-    let mut max_idx = max_frame.min(current_commands.len());
-    let mut frame = painter_id % max_idx;
+    let (mut max_idx, mut frame) = slot.start(&current_commands);
+    let mut sent = 0;
     // loop over frames
     'outer: loop {
+        if is_idle(&current_commands) {
+            // nothing to paint, wait for the next command instead of spinning
+            let Ok(command) = rx.recv() else {
+                break 'outer;
+            };
+            current_commands = command;
+            (max_idx, frame) = slot.start(&current_commands);
+            sent = 0;
+            continue;
+        }
         // loop over drawings of a single frame
-        if client.send_pixel(&current_commands[frame]).is_err() {
+        if client.send_chunk(&current_commands, frame).is_err() {
             break 'outer;
         }
         frame = (frame + 1) % max_idx;
+        sent += 1;
+        if sent == max_idx {
+            client.frame_done();
+            sent = 0;
+        }
         'inner: loop {
             // ordered by likelihood
             match rx.try_recv() {
                 Err(TryRecvError::Empty) => break 'inner,
                 Ok(command) => {
                     current_commands = command;
-                    max_idx = max_frame.min(current_commands.len());
-                    frame = painter_id % max_idx;
+                    (max_idx, frame) = slot.start(&current_commands);
+                    sent = 0;
                 }
                 Err(TryRecvError::Disconnected) => {
                     // cleanly exit in case all senders are dropped
@@ -40,3 +123,55 @@ pub fn painter(
     }
     current_commands
 }
+
+/// Paint an image to the canvas from within a tokio runtime
+/// Always paints the latest command published to the watch channel and returns once either the
+/// connection breaks or the sending side is dropped. Everything sent is counted in `stats`
+#[cfg(feature = "tokio")]
+pub async fn async_painter(
+    rx: &mut watch::Receiver<Arc<Command>>,
+    mut client: AsyncClient,
+    painter_id: usize,
+    max_frame: usize,
+    stats: &Stats,
+) {
+    let mut current_commands = rx.borrow_and_update().clone();
+    let mut max_idx = current_commands.len().max(1);
+    let mut frame = first_chunk(painter_id, max_frame, max_idx);
+    let mut sent = 0;
+    loop {
+        if is_idle(&current_commands) {
+            // nothing to paint, wait for the next command instead of spinning
+            if rx.changed().await.is_err() {
+                break;
+            }
+            current_commands = rx.borrow_and_update().clone();
+            max_idx = current_commands.len().max(1);
+            frame = first_chunk(painter_id, max_frame, max_idx);
+            sent = 0;
+            continue;
+        }
+        if client.send_pixel(&current_commands[frame]).await.is_err() {
+            stats.add_error();
+            break;
+        }
+        stats.add_bytes(current_commands[frame].len());
+        frame = (frame + 1) % max_idx;
+        sent += 1;
+        if sent == max_idx {
+            stats.add_frame();
+            sent = 0;
+        }
+        match rx.has_changed() {
+            Ok(false) => {}
+            Ok(true) => {
+                current_commands = rx.borrow_and_update().clone();
+                max_idx = current_commands.len().max(1);
+                frame = first_chunk(painter_id, max_frame, max_idx);
+                sent = 0;
+            }
+            // cleanly exit in case the sender is dropped
+            Err(_) => break,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Self describing files of encoded frames, compiled ahead of time and painted elsewhere
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use bincode::config::standard;
+use bincode::{Decode, Encode};
+
+use crate::feature_detection::Features;
+use crate::image_handler::{split_chunks, BinaryFormat, BulkFormat, CommandLib, ImageConfig};
+
+/// Start of every payload file
+pub const MAGIC: &[u8; 8] = b"PBPAYLD\0";
+/// Bump when the encoding of payloads, commands or the image config changes
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Encoded frames, together with everything needed to check if a server can paint them
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Payload {
+    /// Config the frames were encoded with, including the pixel formats
+    pub config: ImageConfig,
+    /// Size of the canvas the frames were made for, if it was known
+    pub canvas: Option<(u32, u32)>,
+    /// Frames with their durations
+    pub library: CommandLib,
+}
+
+/// A difference between a payload and the server it is painted on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Binary {
+        payload: BinaryFormat,
+        server: Option<BinaryFormat>,
+    },
+    Bulk {
+        payload: BulkFormat,
+        server: Option<BulkFormat>,
+    },
+    Offset,
+    Gray,
+    Canvas {
+        payload: (u32, u32),
+        server: (u32, u32),
+    },
+}
+
+impl Mismatch {
+    /// If the server doesn't understand the commands of the payload. Otherwise the payload can
+    /// be painted, but might not look as intended
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Mismatch::Canvas { .. })
+    }
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Binary { payload, server } => write!(
+                f,
+                "payload uses the binary format {payload:?}, the server supports {}",
+                server.map_or("no binary format".to_string(), |format| format!(
+                    "{format:?}"
+                ))
+            ),
+            Mismatch::Bulk { payload, server } => write!(
+                f,
+                "payload uses the bulk format {payload:?}, the server supports {}",
+                server.map_or("no bulk format".to_string(), |format| format!("{format:?}"))
+            ),
+            Mismatch::Offset => write!(f, "payload uses the OFFSET command, the server doesn't"),
+            Mismatch::Gray => write!(f, "payload uses the PX x y gg command, the server doesn't"),
+            Mismatch::Canvas { payload, server } => write!(
+                f,
+                "payload was made for a {} x {} canvas, the canvas is {} x {}",
+                payload.0, payload.1, server.0, server.1
+            ),
+        }
+    }
+}
+
+impl Payload {
+    /// Write the payload to `path`, compressed
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let encoded = bincode::encode_to_vec((FORMAT_VERSION, self), standard())?;
+        let mut data = MAGIC.to_vec();
+        data.extend(zstd::encode_all(&encoded[..], 3)?);
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Read a payload written by `Payload::write`
+    pub fn read(path: &Path) -> Result<Payload, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "not a pixelbomber payload").into(),
+            );
+        }
+        let data = zstd::decode_all(file)?;
+        let (version, read): (u32, usize) = bincode::decode_from_slice(&data, standard())?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "payload format {version} is not supported, only {FORMAT_VERSION} is"
+            )
+            .into());
+        }
+        let (payload, _): (Payload, _) = bincode::decode_from_slice(&data[read..], standard())?;
+        if payload.library.is_empty() {
+            return Err("payload doesn't contain any frames".into());
+        }
+        Ok(payload)
+    }
+
+    /// If the file at `path` starts like a payload
+    pub fn is_payload(path: &Path) -> bool {
+        let mut magic = [0; MAGIC.len()];
+        File::open(path).is_ok_and(|mut file| file.read_exact(&mut magic).is_ok())
+            && magic == *MAGIC
+    }
+
+    /// Split the chunks of all frames, so they fit into datagrams of `datagram_size` bytes
+    pub fn fit_datagrams(&mut self, datagram_size: usize) {
+        for frame in &mut self.library {
+            frame.command = Arc::new(split_chunks(&frame.command, datagram_size, &self.config));
+        }
+        self.config.datagram_size = Some(datagram_size);
+    }
+
+    /// Everything about the payload that doesn't match the server with `features`
+    pub fn check(&self, features: &Features) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        if let Some(payload) = self.config.binary {
+            if features.binary != Some(payload) {
+                mismatches.push(Mismatch::Binary {
+                    payload,
+                    server: features.binary,
+                });
+            }
+        }
+        if let Some(payload) = self.config.bulk {
+            if features.bulk != Some(payload) {
+                mismatches.push(Mismatch::Bulk {
+                    payload,
+                    server: features.bulk,
+                });
+            }
+        }
+        if self.config.offset_usage && !features.offset {
+            mismatches.push(Mismatch::Offset);
+        }
+        if self.config.gray_usage && !features.px_gray {
+            mismatches.push(Mismatch::Gray);
+        }
+        let server = (features.width, features.height);
+        if let Some(payload) = self.canvas.filter(|canvas| *canvas != server) {
+            mismatches.push(Mismatch::Canvas { payload, server });
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handler::TimedCommand;
+    use std::time::Duration;
+
+    fn payload(config: ImageConfig) -> Payload {
+        Payload {
+            config,
+            canvas: Some((1920, 1080)),
+            library: vec![TimedCommand::new(
+                vec![b"PX 0 0 ffffff\n".to_vec()],
+                Some(Duration::from_millis(40)),
+            )],
+        }
+    }
+
+    fn features() -> Features {
+        Features {
+            width: 1920,
+            height: 1080,
+            offset: false,
+            px_gray: false,
+            binary: Some(BinaryFormat::CoordLERGBA),
+            bulk: None,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let path = std::env::temp_dir().join(format!("pixelbomber-{}.pbp", std::process::id()));
+        let written = payload(ImageConfig { offset_usage: true, ..ImageConfig::default() });
+        written.write(&path).unwrap();
+        assert!(Payload::is_payload(&path));
+        let read = Payload::read(&path).unwrap();
+        assert_eq!(read.config, written.config);
+        assert_eq!(read.canvas, written.canvas);
+        assert_eq!(read.library.len(), 1);
+        assert_eq!(read.library[0].command, written.library[0].command);
+        assert_eq!(read.library[0].duration, written.library[0].duration);
+        // payloads without frames can't be painted
+        Payload { library: Vec::new(), ..written }.write(&path).unwrap();
+        assert!(Payload::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let plain = payload(ImageConfig::default());
+        assert_eq!(plain.check(&features()), vec![]);
+        let binary = payload(ImageConfig {
+            binary: Some(BinaryFormat::CoordLERGBA),
+            ..ImageConfig::default()
+        });
+        assert_eq!(binary.check(&features()), vec![]);
+        let other = payload(ImageConfig {
+            binary: Some(BinaryFormat::CoordBERGBA),
+            offset_usage: true,
+            gray_usage: true,
+            ..ImageConfig::default()
+        });
+        let mismatches = other.check(&features());
+        assert_eq!(mismatches.len(), 3);
+        assert!(mismatches.iter().all(Mismatch::is_fatal));
+    }
+
+    #[test]
+    fn test_check_canvas() {
+        let payload = payload(ImageConfig::default());
+        let features = Features { width: 800, height: 600, ..features() };
+        let mismatches = payload.check(&features);
+        assert_eq!(
+            mismatches,
+            vec![Mismatch::Canvas { payload: (1920, 1080), server: (800, 600) }]
+        );
+        // the payload can still be painted
+        assert!(!mismatches[0].is_fatal());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use log::{error, warn};
+use tokio::{runtime::Builder, sync::watch, time::sleep};
+
+use crate::{async_painter, image_handler::Command, AsyncClient};
+
+use super::{stats::Stats, Host};
+
+async fn run_painter(
+    mut source: watch::Receiver<Arc<Command>>,
+    host: Host,
+    painter_id: usize,
+    max_frame: usize,
+    stats: Arc<Stats>,
+) {
+    // exit as soon as the sending side is gone, even while reconnecting
+    while source.has_changed().is_ok() {
+        match host.new_async_stream().await {
+            Ok(stream) => {
+                let client = AsyncClient::new(stream);
+                stats.open_connection();
+                async_painter(&mut source, client, painter_id, max_frame, &stats).await;
+                stats.close_connection();
+                if source.has_changed().is_err() {
+                    break;
+                }
+                stats.add_reconnect();
+            }
+            Err(err) => {
+                stats.add_error();
+                warn!("Could not connect to host! ({err:?})");
+            }
+        }
+        warn!("Painter task stopped working, restarting");
+        sleep(Duration::from_secs(5)).await
+    }
+}
+
+/// Runs one painter for each of `stats` as tasks on a tokio runtime with `worker_threads` threads
+pub fn get_async_painters(
+    source: Receiver<Arc<Command>>,
+    host: Host,
+    worker_threads: usize,
+    stats: Vec<Arc<Stats>>,
+) -> impl FnMut() {
+    move || {
+        let runtime = match Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                error!("Unable to start tokio runtime ({err:?})");
+                return;
+            }
+        };
+        // Waits for first frame
+        let Ok(first) = source.recv() else {
+            return;
+        };
+        let (sink, painter_source) = watch::channel(first);
+        let threads = stats.len();
+        let handles: Vec<_> = stats
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| {
+                runtime.spawn(run_painter(
+                    painter_source.clone(),
+                    host.clone(),
+                    i,
+                    threads,
+                    stats.clone(),
+                ))
+            })
+            .collect();
+        drop(painter_source);
+        while let Ok(command) = source.recv() {
+            // only fails if all painters stopped
+            if sink.send(command).is_err() {
+                break;
+            }
+        }
+        drop(sink);
+        runtime.block_on(async {
+            for handle in handles {
+                let _ = handle.await;
+            }
+        });
+    }
+}
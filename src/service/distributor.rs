@@ -1,11 +1,14 @@
 use std::sync::{
     mpsc::{Receiver, SyncSender, TrySendError},
-    Arc,
+    Arc, Mutex,
 };
 
 use image::DynamicImage;
 
-use crate::image_handler::{Command, ImageConfig};
+use crate::{
+    image_handler::{Command, ImageConfig},
+    Slot,
+};
 
 use super::converter::ConverterChange;
 
@@ -42,17 +45,27 @@ pub fn get_converter_distributor(
     }
 }
 
+/// The painters of a service, painters can be added and removed while the service is running
+#[derive(Default)]
+pub struct PainterPool {
+    pub sinks: Vec<(SyncSender<Arc<Command>>, Arc<Slot>)>,
+    /// The latest command, new painters start with it
+    pub current: Option<Arc<Command>>,
+}
+
 pub fn get_painter_distributor(
     source: Receiver<Arc<Command>>,
-    sinks: Vec<SyncSender<Arc<Command>>>,
+    pool: Arc<Mutex<PainterPool>>,
 ) -> impl FnMut() {
     move || {
         while let Ok(command) = source.recv() {
-            for sink in &sinks {
+            let mut pool = pool.lock().unwrap();
+            for (sink, _) in &pool.sinks {
                 if let Err(TrySendError::Disconnected(_)) = sink.try_send(command.clone()) {
                     break;
                 }
             }
+            pool.current = Some(command);
         }
     }
 }
@@ -2,22 +2,67 @@ use std::ffi::OsString;
 use rand::seq::IndexedRandom;
 use rand::{rng};
 use std::io;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
 use std::str::FromStr;
 use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
 use socket2::{SockAddr, Domain, Socket, Type};
 use trust_dns_resolver::Resolver;
 use url::Url;
+use bincode::{Decode, Encode};
+use crate::{connection::MAX_MESSAGE_SIZE, Connection};
+use super::udp::DATAGRAM_SIZE;
+#[cfg(feature = "websocket")]
+use crate::WebSocketStream;
+
+/// Transport used to talk to the pixelflut server
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum Protocol {
+    /// Plain TCP, used if no scheme is specified
+    Tcp,
+    /// Plain UDP, selected with `udp://`. Each datagram carries multiple pixel commands
+    Udp,
+    /// Pixelflut over a WebSocket, selected with `ws://`. Every chunk is sent as one binary message
+    WebSocket,
+    /// Text or binary protocol over TLS, selected with `tls://`
+    Tls,
+}
+
+impl Protocol {
+    /// Largest chunk that can be sent at once, as every chunk is sent as a single datagram or
+    /// WebSocket message
+    pub fn max_chunk_size(self) -> Option<usize> {
+        match self {
+            Protocol::Udp => Some(DATAGRAM_SIZE),
+            Protocol::WebSocket => Some(MAX_MESSAGE_SIZE),
+            Protocol::Tcp | Protocol::Tls => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Host {
     pub addr: Vec<IpAddr>,
     pub bind: Option<String>,
     pub port: u16,
+    pub protocol: Protocol,
+    /// Host name as specified, sent during the WebSocket handshake
+    pub name: String,
+    /// Path of the WebSocket endpoint
+    pub path: String,
 }
 
 impl Host {
     pub fn new(host_str: &str, bind_addr: Option<String>) -> Result<Host, String> {
+        let (protocol, host_str) = match host_str.split_once("://") {
+            Some(("tcp", host)) => (Protocol::Tcp, host),
+            Some(("udp", host)) => (Protocol::Udp, host),
+            #[cfg(feature = "websocket")]
+            Some(("ws", host)) => (Protocol::WebSocket, host),
+            #[cfg(feature = "tls")]
+            Some(("tls", host)) => (Protocol::Tls, host),
+            Some((scheme, _)) => return Err(format!("Unsupported scheme {scheme}")),
+            None => (Protocol::Tcp, host_str),
+        };
         // this ensures that it is a valid url
         let host_str = format!("http://{}", host_str);
         let url = Url::parse(&host_str).map_err(|err| err.to_string())?;
@@ -45,24 +90,93 @@ impl Host {
             return Err("No address found".to_string());
         }
         let port = url.port().ok_or_else(|| "No port specified".to_string())?;
-        Self::from_raw(addr, port, bind_addr)
+        let name = url.host_str().unwrap().to_string();
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
+        Ok(Host {
+            protocol,
+            name,
+            path,
+            ..Self::from_raw(addr, port, bind_addr)?
+        })
     }
 
     pub fn from_raw(addr: Vec<IpAddr>, port: u16, bind: Option<String>) -> Result<Host, String> {
-        Ok(Host { addr, port, bind })
-
+        let name = match addr.first() {
+            Some(IpAddr::V6(addr)) => format!("[{addr}]"),
+            Some(addr) => addr.to_string(),
+            None => String::new(),
+        };
+        Ok(Host {
+            addr,
+            port,
+            bind,
+            protocol: Protocol::Tcp,
+            name,
+            path: "/".to_string(),
+        })
     }
 
-    pub fn new_stream(&self) -> io::Result<TcpStream> {
+    fn new_socket(&self, socket_type: Type) -> io::Result<(Socket, SocketAddr)> {
         let addr = *self.addr.choose(&mut rng()).unwrap();
         let socket_addr = SocketAddr::new(addr, self.port);
-        let socket = Socket::new(Domain::for_address(socket_addr), Type::STREAM, None)?;
+        let socket = Socket::new(Domain::for_address(socket_addr), socket_type, None)?;
         if let Some(bind) = &self.bind {
             let name = OsString::from(bind);
-            setsockopt(&socket, BindToDevice, &name)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            setsockopt(&socket, BindToDevice, &name).map_err(io::Error::other)?;
         };
+        Ok((socket, socket_addr))
+    }
+
+    pub fn new_stream(&self) -> io::Result<TcpStream> {
+        let (socket, socket_addr) = self.new_socket(Type::STREAM)?;
+        socket.connect(&SockAddr::from(socket_addr))?;
+        Ok(socket.into())
+    }
+
+    /// Open a stream based connection using the protocol of the host
+    pub fn new_connection(&self) -> io::Result<Connection> {
+        match self.protocol {
+            Protocol::Tcp => Ok(self.new_stream()?.into()),
+            Protocol::Udp => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "UDP is not stream based",
+            )),
+            #[cfg(feature = "websocket")]
+            Protocol::WebSocket => {
+                let url = format!("ws://{}:{}{}", self.name, self.port, self.path);
+                Ok(WebSocketStream::connect(&url, self.new_stream()?)?.into())
+            }
+            #[cfg(not(feature = "websocket"))]
+            Protocol::WebSocket => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "WebSocket support is not enabled",
+            )),
+            #[cfg(feature = "tls")]
+            Protocol::Tls => Ok(crate::tls::connect(&self.name, self.new_stream()?)?.into()),
+            #[cfg(not(feature = "tls"))]
+            Protocol::Tls => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS support is not enabled",
+            )),
+        }
+    }
+
+    /// Create a UDP socket that sends to the host
+    pub fn new_udp_socket(&self) -> io::Result<UdpSocket> {
+        let (socket, socket_addr) = self.new_socket(Type::DGRAM)?;
         socket.connect(&SockAddr::from(socket_addr))?;
         Ok(socket.into())
     }
+
+    /// Connect to the host without blocking the tokio runtime
+    #[cfg(feature = "tokio")]
+    pub async fn new_async_stream(&self) -> io::Result<tokio::net::TcpStream> {
+        let (socket, socket_addr) = self.new_socket(Type::STREAM)?;
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::TcpSocket::from_std_stream(socket.into());
+        socket.connect(socket_addr).await
+    }
 }
@@ -0,0 +1,59 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::stats::StatsSnapshot;
+
+/// How often the painters are looked at
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of samples connections have to stay open for to count as stable
+const WINDOW: usize = 10;
+
+/// Finds the number of connections a server accepts, by watching painters that can't connect
+/// or lose their connection while others stay connected
+#[derive(Default)]
+pub(crate) struct LimitDetector {
+    /// Open connections and failures so far
+    samples: VecDeque<(u64, u64)>,
+    last_sample: Option<Instant>,
+    limit: Option<usize>,
+}
+
+impl LimitDetector {
+    /// Take a sample if it is time to, returns the largest number of connections that stayed
+    /// open while other painters failed, once that happened
+    pub(crate) fn sample(&mut self, stats: StatsSnapshot) -> Option<usize> {
+        if self
+            .last_sample
+            .is_some_and(|last| last.elapsed() < SAMPLE_INTERVAL)
+        {
+            return self.limit;
+        }
+        self.last_sample = Some(Instant::now());
+        self.samples
+            .push_back((stats.connections, stats.errors + stats.reconnects));
+        if self.samples.len() > WINDOW {
+            self.samples.pop_front();
+        }
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return self.limit;
+        };
+        let failing = last.1 > first.1;
+        let stable = self
+            .samples
+            .iter()
+            .map(|sample| sample.0)
+            .min()
+            .unwrap_or(0) as usize;
+        if self.samples.len() == WINDOW && failing && stable > 0 && stable < stats.painters {
+            self.limit = Some(stable);
+        }
+        self.limit
+    }
+
+    /// Forget the samples, the number of painters changed
+    pub(crate) fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
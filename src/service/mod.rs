@@ -1,23 +1,30 @@
+#[cfg(feature = "tokio")]
+mod async_painter;
 mod converter;
 mod distributor;
 mod host;
+mod limit;
 mod merger;
 mod painter;
+mod repair;
+mod udp;
 pub mod moderator;
+pub mod stats;
 
 use std::{
+    io,
     sync::{
-        mpsc::{sync_channel, SyncSender},
-        Arc,
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
     },
     thread::{spawn, JoinHandle},
 };
 
-pub use host::Host;
+pub use host::{Host, Protocol};
 
 use crate::{
-    image_handler::{Command, ImageConfig},
-    Client,
+    image_handler::{is_idle, split_chunks, Command, CommandLib, ImageConfig},
+    Client, Slot,
 };
 use crate::service::moderator::Server;
 
@@ -28,6 +35,14 @@ pub struct ServiceBuilder {
     converter_threads: usize,
     channel_limit: usize,
     listen_port: Option<u16>,
+    #[cfg(feature = "tokio")]
+    async_worker_threads: Option<usize>,
+    zero_copy: bool,
+    repair: bool,
+    manager_secret: Vec<u8>,
+    manager_images: bool,
+    #[cfg(feature = "tls")]
+    manager_tls: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl ServiceBuilder {
@@ -40,6 +55,14 @@ impl ServiceBuilder {
             converter_threads: 1,
             channel_limit: 10,
             listen_port: None,
+            #[cfg(feature = "tokio")]
+            async_worker_threads: None,
+            zero_copy: false,
+            repair: false,
+            manager_secret: Vec::new(),
+            manager_images: false,
+            #[cfg(feature = "tls")]
+            manager_tls: None,
         }
     }
 
@@ -80,15 +103,80 @@ impl ServiceBuilder {
         self
     }
 
+    /// Run the painters as tasks on a tokio runtime with the given number of worker threads
+    /// rather than spawning one thread per painter. This allows for a lot more connections
+    /// than there are cores
+    #[cfg(feature = "tokio")]
+    pub fn async_runtime(mut self, worker_threads: usize) -> ServiceBuilder {
+        self.async_worker_threads = Some(worker_threads);
+        self
+    }
+
+    /// Send commands with `MSG_ZEROCOPY` instead of copying them into the kernel
+    /// Only available on Linux, other platforms fall back to regular writes
+    pub fn zero_copy(mut self, zero_copy: bool) -> ServiceBuilder {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    /// Only paint pixels that differ from the target image
+    /// The canvas is read back with `PX x y` commands and compared to the image sent with
+    /// `Service::send_image`, so only damaged pixels are painted again
+    /// WARNING: Reading the canvas uses an additional connection
+    pub fn repair(mut self, repair: bool) -> ServiceBuilder {
+        self.repair = repair;
+        self
+    }
+
+    /// Shared secret clients of the management server have to know, required with `listen_port`
+    /// Clients pass it with `moderator::Client::with_credentials`
+    pub fn manager_secret(mut self, secret: impl Into<Vec<u8>>) -> ServiceBuilder {
+        self.manager_secret = secret.into();
+        self
+    }
+
+    /// Let the management server send images to its clients instead of encoded commands
+    /// The clients encode their part of the image themselves, which saves a lot of bandwidth
+    /// NOTE: only images sent with `Service::send_image` are distributed this way
+    pub fn manager_images(mut self, manager_images: bool) -> ServiceBuilder {
+        self.manager_images = manager_images;
+        self
+    }
+
+    /// Encrypt the connections of the management server to its clients with TLS
+    /// Clients should pin the certificate, see `moderator::Client::new_pinned`
+    #[cfg(feature = "tls")]
+    pub fn manager_tls(mut self, config: Arc<rustls::ServerConfig>) -> ServiceBuilder {
+        self.manager_tls = Some(config);
+        self
+    }
+
     pub fn build(self) -> Service {
-        Service::new(
+        let service = Service::new(
             self.host,
             self.threads,
             self.image_config,
             self.converter_threads,
             self.channel_limit,
             self.listen_port,
-        )
+        );
+        #[cfg(feature = "tokio")]
+        let service = Service {
+            async_worker_threads: self.async_worker_threads,
+            ..service
+        };
+        #[cfg(feature = "tls")]
+        let service = Service {
+            manager_tls: self.manager_tls,
+            ..service
+        };
+        Service {
+            zero_copy: self.zero_copy,
+            repair: self.repair,
+            manager_secret: self.manager_secret,
+            manager_images: self.manager_images,
+            ..service
+        }
     }
 }
 
@@ -102,7 +190,28 @@ pub struct Service {
     listen_port: Option<u16>,
     converter_input: Option<SyncSender<distributor::DistributorChange>>,
     painter_input: Option<SyncSender<Arc<Command>>>,
+    painters: Arc<Mutex<distributor::PainterPool>>,
     join_handles: Vec<JoinHandle<()>>,
+    #[cfg(feature = "tokio")]
+    async_worker_threads: Option<usize>,
+    zero_copy: bool,
+    repair: bool,
+    repair_input: Option<SyncSender<repair::RepairChange>>,
+    manager_secret: Vec<u8>,
+    manager_images: bool,
+    manager_image_input: Option<SyncSender<(image::DynamicImage, ImageConfig)>>,
+    playback_input: Option<SyncSender<moderator::Playback>>,
+    /// Animation set with `Service::preload`
+    library: CommandLib,
+    /// Counters of every painter, in the order of their slots
+    painter_stats: Vec<Arc<stats::Stats>>,
+    /// Combined counters of painters that were removed
+    retired_stats: stats::StatsSnapshot,
+    limit_detector: limit::LimitDetector,
+    /// Reports of the clients, if this is a manager
+    manager_status: moderator::Status,
+    #[cfg(feature = "tls")]
+    manager_tls: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl Service {
@@ -114,6 +223,7 @@ impl Service {
         channel_limit: usize,
         listen_port: Option<u16>,
     ) -> Service {
+        let image_config = for_protocol(image_config, host.protocol);
         Service {
             host,
             threads,
@@ -124,7 +234,24 @@ impl Service {
             listen_port,
             converter_input: None,
             painter_input: None,
+            painters: Arc::default(),
             join_handles: Vec::new(),
+            #[cfg(feature = "tokio")]
+            async_worker_threads: None,
+            zero_copy: false,
+            repair: false,
+            repair_input: None,
+            manager_secret: Vec::new(),
+            manager_images: false,
+            manager_image_input: None,
+            playback_input: None,
+            library: Vec::new(),
+            painter_stats: Vec::new(),
+            retired_stats: stats::StatsSnapshot::default(),
+            limit_detector: limit::LimitDetector::default(),
+            manager_status: moderator::Status::default(),
+            #[cfg(feature = "tls")]
+            manager_tls: None,
         }
     }
 
@@ -134,9 +261,27 @@ impl Service {
         if self.painter_input.is_some() {
             panic!("Can not start Service twice!")
         }
+        if self.listen_port.is_some() && self.manager_secret.is_empty() {
+            panic!("A management server needs a secret, see ServiceBuilder::manager_secret")
+        }
         let (painter_input, painter_output) = sync_channel(self.channel_limit);
         self.painter_input = Some(painter_input.clone());
-        if self.converter_threads > 0 {
+        let mut manager_images = None;
+        if self.repair {
+            let (repair_input, repair_output) = sync_channel(self.channel_limit);
+            self.repair_input = Some(repair_input);
+            self.join_handles.push(spawn(repair::get_repairer(
+                repair_output,
+                painter_input.clone(),
+                self.host.clone(),
+                self.image_config,
+            )));
+        } else if self.listen_port.is_some() && self.manager_images {
+            // the clients encode the images themselves
+            let (image_input, image_output) = sync_channel(self.channel_limit);
+            self.manager_image_input = Some(image_input);
+            manager_images = Some(image_output);
+        } else if self.converter_threads > 0 {
             let (merger_input, merger_output) = sync_channel(self.channel_limit);
             let mut distributor_output = Vec::new();
             for _ in 0..self.converter_threads {
@@ -161,28 +306,141 @@ impl Service {
             )));
         }
         if let Some(port) = self.listen_port {
-            let server = Server::new(port, self.host.clone(), self.threads, painter_output);
+            let server = Server::new(port, self.host.clone(), self.threads, painter_output)
+                .secret(self.manager_secret.clone())
+                .config(self.image_config)
+                .status(self.manager_status.clone());
+            let server = match manager_images {
+                Some(images) => server.images(images),
+                None => server,
+            };
+            let (playback_input, playback_output) = sync_channel(self.channel_limit);
+            self.playback_input = Some(playback_input);
+            let server = server.playback(playback_output);
+            #[cfg(feature = "tls")]
+            let server = match self.manager_tls.clone() {
+                Some(config) => server.tls(config),
+                None => server,
+            };
             self.join_handles.push(spawn(move|| {
                 server.listen()
             }))
         } else {
-            let mut painter_inputs = Vec::new();
-            for i in 0..self.threads {
-                let (painter_input, painter_output) = sync_channel(self.channel_limit);
-                painter_inputs.push(painter_input);
-                self.join_handles.push(spawn(painter::get_painter(
-                    painter_output,
-                    self.host.clone(),
-                    i,
-                    self.threads,
-                )));
+            self.start_painters(painter_output);
+        }
+    }
+
+    fn start_painters(&mut self, painter_output: Receiver<Arc<Command>>) {
+        self.painter_stats = (0..self.threads).map(|_| Arc::default()).collect();
+        #[cfg(feature = "tokio")]
+        if let (Some(worker_threads), Protocol::Tcp) = (self.async_worker_threads, self.host.protocol) {
+            self.join_handles.push(spawn(async_painter::get_async_painters(
+                painter_output,
+                self.host.clone(),
+                worker_threads,
+                self.painter_stats.clone(),
+            )));
+            return;
+        }
+        #[cfg(feature = "tokio")]
+        if let Some(worker_threads) = self.async_worker_threads {
+            log::warn!(
+                "The async runtime only supports plain TCP, ignoring {worker_threads} worker threads for {:?}",
+                self.host.protocol
+            );
+        }
+        let painters = (0..self.threads)
+            .map(|i| self.spawn_painter(i, self.threads, self.painter_stats[i].clone()))
+            .collect();
+        self.painters.lock().unwrap().sinks = painters;
+        self.join_handles
+            .push(spawn(distributor::get_painter_distributor(
+                painter_output,
+                self.painters.clone(),
+            )));
+    }
+
+    fn spawn_painter(
+        &mut self,
+        id: usize,
+        count: usize,
+        stats: Arc<stats::Stats>,
+    ) -> (SyncSender<Arc<Command>>, Arc<Slot>) {
+        let (painter_input, painter_output) = sync_channel(self.channel_limit);
+        let slot = Arc::new(Slot::new(id, count));
+        self.join_handles.push(spawn(painter::get_painter(
+            painter_output,
+            self.host.clone(),
+            slot.clone(),
+            self.zero_copy,
+            stats,
+        )));
+        (painter_input, slot)
+    }
+
+    /// Change the number of painter threads, also while the service is running
+    /// Painters are added or removed, and all painters get their new position, so the chunks
+    /// stay evenly split between them
+    /// NOTE: not supported by a management server, and painters on the tokio runtime
+    pub fn set_threads(&mut self, threads: usize) -> io::Result<()> {
+        if threads == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one painter is needed"));
+        }
+        if self.painter_input.is_none() {
+            self.threads = threads;
+            return Ok(());
+        }
+        if self.listen_port.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Clients of a manager get the number of painters when they connect",
+            ));
+        }
+        #[cfg(feature = "tokio")]
+        if let (Some(_), Protocol::Tcp) = (self.async_worker_threads, self.host.protocol) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The number of painters on the tokio runtime is fixed",
+            ));
+        }
+        let painters = self.painters.clone();
+        let mut pool = painters.lock().unwrap();
+        // dropping the sender stops the painter
+        pool.sinks.truncate(threads);
+        // painters that were removed earlier are gone by now
+        self.join_handles.retain(|handle| !handle.is_finished());
+        for removed in self.painter_stats.drain(threads.min(self.painter_stats.len())..) {
+            let removed = stats::StatsSnapshot {
+                connections: 0,
+                ..removed.snapshot(0)
+            };
+            self.retired_stats = self.retired_stats + removed;
+        }
+        while pool.sinks.len() < threads {
+            let stats = Arc::new(stats::Stats::default());
+            let painter = self.spawn_painter(pool.sinks.len(), threads, stats.clone());
+            self.painter_stats.push(stats);
+            pool.sinks.push(painter);
+        }
+        for (i, (sink, slot)) in pool.sinks.iter().enumerate() {
+            slot.set(i, threads);
+            // painters apply their new slot with the next command
+            if let Some(current) = &pool.current {
+                let _ = sink.try_send(current.clone());
             }
-            self.join_handles
-                .push(spawn(distributor::get_painter_distributor(
-                    painter_output,
-                    painter_inputs,
-                )));
         }
+        self.threads = threads;
+        self.limit_detector.reset();
+        Ok(())
+    }
+
+    /// The number of connections the server accepts, once the painters ran into a limit
+    /// This is the largest number of connections that stayed open for 10s while other painters
+    /// were refused or dropped by the server. Call this regularly, e.g. from the callback of
+    /// `Service::loop_callback`, it samples the painters at most once per second
+    pub fn connection_limit(&mut self) -> Option<usize> {
+        let stats = self.stats();
+        self.limit_detector.sample(stats)
     }
 
     fn start_check(&self) {
@@ -195,16 +453,38 @@ impl Service {
     /// WARNING: This will wait for all converter threads until their queue is
     /// empty enough
     pub fn change_image_config(&mut self, image_config: ImageConfig) {
+        let image_config = for_protocol(image_config, self.host.protocol);
         self.image_config = image_config;
+        if let Some(playback_input) = &self.playback_input {
+            let _ = playback_input.send(moderator::Playback::Config(image_config));
+        }
+        if let Some(repair_input) = &self.repair_input {
+            let _ = repair_input.send(repair::RepairChange::Config(image_config));
+        }
         if let Some(converter_input) = &self.converter_input {
             let _ = converter_input.send(distributor::DistributorChange::Config(image_config));
         }
     }
 
+    /// Prepare `command`, encoded with `config`, for the connections of the painters
+    /// Only UDP and WebSockets need this, where every chunk has to fit into a datagram or message
+    pub fn fit_to_host(&self, command: Arc<Command>, config: &ImageConfig) -> Arc<Command> {
+        match self.image_config.datagram_size {
+            Some(datagram_size) if config.datagram_size != Some(datagram_size) => {
+                Arc::new(split_chunks(&command, datagram_size, config))
+            }
+            _ => command,
+        }
+    }
+
     /// Send an image to be processed and painted afterwards
     pub fn send_image(&self, image: image::DynamicImage) {
         self.start_check();
-        if let Some(converter_input) = &self.converter_input {
+        if let Some(repair_input) = &self.repair_input {
+            let _ = repair_input.try_send(repair::RepairChange::Image(image));
+        } else if let Some(manager_image_input) = &self.manager_image_input {
+            let _ = manager_image_input.try_send((image, self.image_config));
+        } else if let Some(converter_input) = &self.converter_input {
             let _ = converter_input.try_send(distributor::DistributorChange::Image(image));
         } else {
             panic!("Cannot send image without converter threads!")
@@ -217,6 +497,56 @@ impl Service {
         let _ = self.painter_input.as_ref().unwrap().send(command);
     }
 
+    /// Set an animation that is later played with `Service::show_frame`
+    /// A management server sends the animation to every client only once, and from then on
+    /// only which frame to show
+    pub fn preload(&mut self, library: CommandLib) {
+        self.start_check();
+        if let Some(playback_input) = &self.playback_input {
+            let _ = playback_input.send(moderator::Playback::Preload(library.clone()));
+        }
+        self.library = library;
+    }
+
+    /// Paint a frame of the animation set with `Service::preload`
+    /// Frames without any pixels, like deltas of unchanged frames, keep the previous frame painted
+    pub fn show_frame(&self, frame: usize) {
+        self.start_check();
+        let Some(timed) = self.library.get(frame) else {
+            log::warn!("Frame {frame} was never preloaded");
+            return;
+        };
+        if let Some(playback_input) = &self.playback_input {
+            let _ = playback_input.send(moderator::Playback::Show(frame));
+        } else if !is_idle(&timed.command) {
+            self.send_command(timed.command.clone());
+        }
+    }
+
+    /// Statistics of all painters of this service
+    pub fn stats(&self) -> stats::StatsSnapshot {
+        let total: stats::StatsSnapshot = self.painter_stats().into_iter().sum();
+        stats::StatsSnapshot {
+            painters: self.threads,
+            ..total + self.retired_stats
+        }
+    }
+
+    /// Statistics of every painter, in the order of their painter ids
+    pub fn painter_stats(&self) -> Vec<stats::StatsSnapshot> {
+        self.painter_stats.iter().map(|stats| stats.snapshot(1)).collect()
+    }
+
+    /// If this service is a management server instead of a fluter
+    pub fn is_manager(&self) -> bool {
+        self.listen_port.is_some()
+    }
+
+    /// Reports of the clients of this manager, stays empty for other services
+    pub fn manager_status(&self) -> moderator::Status {
+        self.manager_status.clone()
+    }
+
     /// Join all threads
     /// This will likely never exit if the service is running
     pub fn join(&mut self) {
@@ -227,6 +557,9 @@ impl Service {
 
     /// Stop the service and all associated threads
     pub fn stop(&mut self) {
+        self.repair_input = None;
+        self.manager_image_input = None;
+        self.playback_input = None;
         self.converter_input = None;
         self.painter_input = None;
         self.join();
@@ -245,7 +578,7 @@ impl Service {
             }
         }
         if self.worker_client.is_none() {
-            self.worker_client = Some(Client::new(self.host.new_stream()?));
+            self.worker_client = Some(Client::new(self.host.new_connection()?));
         }
         Ok(self.worker_client.as_mut().unwrap())
     }
@@ -267,3 +600,14 @@ impl Service {
         }
     }
 }
+
+/// Commands for UDP and WebSocket hosts need chunks that fit into a datagram or message
+fn for_protocol(image_config: ImageConfig, protocol: Protocol) -> ImageConfig {
+    match protocol.max_chunk_size() {
+        Some(size) => ImageConfig {
+            datagram_size: Some(size),
+            ..image_config
+        },
+        None => image_config,
+    }
+}
@@ -1,146 +1,1168 @@
+use std::collections::VecDeque;
 use std::error::Error;
 use std::io;
 use std::io::{Read, Write};
-use std::net::{IpAddr, TcpListener, TcpStream};
-use std::sync::Arc;
-use std::sync::mpsc::{Receiver, TryRecvError};
-use std::thread::sleep;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 use bincode::{encode_to_vec, Decode, Encode};
 use bincode::config::standard;
-use log::warn;
-use crate::image_handler::Command;
-use crate::service::{Host, Service};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+use sha2::Sha256;
+use image::{DynamicImage, RgbaImage};
+use crate::image_handler::{
+    is_idle, partition, prepare_image, share, split_bands, Command, CommandLib, ImageConfig,
+    TimedCommand,
+};
+use crate::service::{stats::StatsSnapshot, Host, Protocol, Service};
+#[cfg(feature = "tls")]
+use crate::tls::{self, Pin};
+
+/// Version of the manager protocol, peers with a different version are rejected
+pub const PROTOCOL_VERSION: u32 = 8;
+/// How long the handshake with a peer may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clients that connect while this many others are still in the handshake are dropped
+const MAX_HANDSHAKES: usize = 16;
+/// Largest message during the handshake and largest report of a client, compressed and
+/// decompressed
+const HANDSHAKE_LIMIT: usize = 4 * 1024;
+/// Largest update of the manager, compressed and decompressed
+const MESSAGE_LIMIT: usize = 1 << 30;
+/// Animations are preloaded in parts of about this many bytes of commands, so they stay well below
+/// `MESSAGE_LIMIT` and don't have to be encoded at once
+const PRELOAD_PART: usize = 64 << 20;
+/// How often clients report their statistics, and the manager checks for new reports
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Clients that didn't report for this long are considered dead
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+const CLIENT_LABEL: &[u8] = b"pixelbomber client";
+const SERVER_LABEL: &[u8] = b"pixelbomber server";
+
+type Nonce = [u8; 32];
+type Tag = [u8; 32];
+
+/// Connection between the manager and a client, possibly encrypted
+trait Link: Read + Write + Send {
+    /// The underlying socket, to wait for it to become readable or writable
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Link for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Link for tls::TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Link for tls::TlsServerStream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
 
 pub struct Server {
     listen_port: u16,
-    host: Host,
-    threads: usize,
-    clients: Vec<TcpStream>,
-    data: Receiver<Arc<Command>>
+    clients: Vec<Peer>,
+    status: Status,
+    data: Option<Receiver<Arc<Command>>>,
+    images: Option<Receiver<(DynamicImage, ImageConfig)>>,
+    playback: Option<Receiver<Playback>>,
+    /// Encoded parts of the animation every client gets once it connects
+    library: Vec<Message>,
+    /// What the commands from `data` and `playback` are encoded with
+    config: ImageConfig,
+    acceptor: Acceptor,
+    /// Number of clients that are still in the handshake
+    handshakes: Arc<AtomicUsize>,
+}
+
+/// Authenticates new clients, this runs on its own thread for every client
+#[derive(Clone)]
+struct Acceptor {
+    target: Target,
+    secret: Vec<u8>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
+}
+
+/// Everything that wakes up the event loop of the server, besides the sockets
+enum Event {
+    Frame(Frame),
+    Preload(CommandLib),
+    Config(ImageConfig),
+    Joined(Peer),
+    /// One of the inputs of the server is gone
+    Stopped,
+}
+
+/// Hands events to the event loop and wakes it up
+#[derive(Clone)]
+struct Notifier {
+    events: Sender<Event>,
+    waker: Arc<UnixStream>,
+}
+
+impl Notifier {
+    /// Returns false once the event loop is gone
+    fn send(&self, event: Event) -> bool {
+        if self.events.send(event).is_err() {
+            return false;
+        }
+        // the waker is non blocking, if it is full the event loop will wake up anyway
+        let _ = (&*self.waker).write(&[0]);
+        true
+    }
+}
+
+/// A length prefixed and compressed message, ready to be sent
+type Message = Arc<[u8]>;
+
+/// A message waiting to be sent to a client
+struct Outgoing {
+    message: Message,
+    /// Frames may be dropped for newer frames, everything else has to arrive
+    frame: bool,
+}
+
+/// A connected client, as seen by the manager
+struct Peer {
+    link: Box<dyn Link>,
+    inbox: Inbox,
+    addr: SocketAddr,
+    stats: StatsSnapshot,
+    last_seen: Instant,
+    queue: VecDeque<Outgoing>,
+    /// How much of the first message in `queue` has already been sent
+    written: usize,
+    /// The link holds back data that couldn't be sent yet
+    flushing: bool,
+    /// Frames that were replaced by newer ones before they could be sent
+    dropped: u64,
 }
 
+impl Peer {
+    fn new(link: Box<dyn Link>, addr: SocketAddr) -> Peer {
+        Peer {
+            link,
+            inbox: Inbox::new(HANDSHAKE_LIMIT),
+            addr,
+            stats: StatsSnapshot::default(),
+            last_seen: Instant::now(),
+            queue: VecDeque::new(),
+            written: 0,
+            flushing: false,
+            dropped: 0,
+        }
+    }
+
+    /// Queue `message`, a frame replaces all frames that are still waiting
+    fn push(&mut self, outgoing: Outgoing) {
+        if outgoing.frame {
+            let count = self.queue.len();
+            // a message that is partially sent can't be taken back
+            let started = usize::from(self.written > 0);
+            let mut position = 0;
+            self.queue.retain(|queued| {
+                position += 1;
+                position <= started || !queued.frame
+            });
+            self.dropped += (count - self.queue.len()) as u64;
+        }
+        self.queue.push_back(outgoing);
+    }
+
+    /// Send as much of the queue as possible without blocking
+    fn send(&mut self) -> io::Result<()> {
+        while let Some(outgoing) = self.queue.front() {
+            match self.link.write(&outgoing.message[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            if self.written == outgoing.message.len() {
+                self.queue.pop_front();
+                self.written = 0;
+            }
+        }
+        // TLS might still hold back the end of the message
+        match self.link.flush() {
+            Ok(()) => self.flushing = false,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.flushing = true,
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Read all reports that arrived, fails if the link is closed
+    fn receive(&mut self) -> Result<(), Box<dyn Error>> {
+        let received = self.inbox.received;
+        while let Some(report) = self.inbox.poll::<Report>(&mut self.link)? {
+            self.stats = report.stats;
+        }
+        // a client that is still sending a report is alive as well
+        if self.inbox.received != received {
+            self.last_seen = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn interest(&self) -> PollFlags {
+        if self.queue.is_empty() && !self.flushing {
+            PollFlags::POLLIN
+        } else {
+            PollFlags::POLLIN | PollFlags::POLLOUT
+        }
+    }
+}
+
+/// What the manager knows about one of its clients
+#[derive(Clone, Debug)]
+pub struct ClientStatus {
+    pub addr: SocketAddr,
+    /// Statistics of the latest report
+    pub stats: StatsSnapshot,
+    /// When the latest data of the client arrived
+    pub last_seen: Instant,
+    /// Frames that were skipped because the client couldn't keep up
+    pub dropped: u64,
+}
+
+/// Shared view of the clients of a `Server`, updated with every heartbeat
+#[derive(Clone, Default)]
+pub struct Status(Arc<Mutex<Vec<ClientStatus>>>);
+
+impl Status {
+    /// All currently connected clients
+    pub fn clients(&self) -> Vec<ClientStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Statistics of all connected clients combined
+    pub fn total(&self) -> StatsSnapshot {
+        self.0.lock().unwrap().iter().map(|client| client.stats).sum()
+    }
+
+    fn update(&self, peers: &[Peer]) {
+        *self.0.lock().unwrap() = peers
+            .iter()
+            .map(|peer| ClientStatus {
+                addr: peer.addr,
+                stats: peer.stats,
+                last_seen: peer.last_seen,
+                dropped: peer.dropped,
+            })
+            .collect();
+    }
+}
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let clients = self.clients();
+        let total: StatsSnapshot = clients.iter().map(|client| client.stats).sum();
+        let dropped: u64 = clients.iter().map(|client| client.dropped).sum();
+        write!(
+            f,
+            "{} client(s), {total}, {dropped} frame(s) dropped",
+            clients.len()
+        )
+    }
+}
+
+/// First message of the handshake, sent by the server
+#[derive(Decode, Encode, Debug)]
+struct Hello {
+    version: u32,
+    nonce: Nonce,
+}
+
+/// Proof of the client that it knows the secret
 #[derive(Decode, Encode, Debug)]
+struct Auth {
+    version: u32,
+    nonce: Nonce,
+    tag: Tag,
+}
+
+/// Proof of the server that it knows the secret
+#[derive(Decode, Encode, Debug)]
+struct Welcome {
+    tag: Tag,
+}
+
+/// What a client should paint
+#[derive(Decode, Encode)]
+enum Update {
+    /// Already encoded pixel commands, clients need `config` to split them into datagrams
+    Command { command: Command, config: ImageConfig },
+    /// A prepared image, to be encoded by the client
+    Image {
+        width: u32,
+        height: u32,
+        rgba: Vec<u8>,
+        config: ImageConfig,
+    },
+    /// Part of an animation, that is later played with `Show`. The first part replaces the
+    /// previous animation, the others are appended to it
+    Preload {
+        frames: CommandLib,
+        config: ImageConfig,
+        first: bool,
+    },
+    /// Paint share `part` of `parts` of a preloaded frame
+    Show {
+        frame: usize,
+        part: usize,
+        parts: usize,
+    },
+}
+
+/// Heartbeat of a client, sent every `HEARTBEAT_INTERVAL`
+#[derive(Decode, Encode, Debug)]
+struct Report {
+    stats: StatsSnapshot,
+}
+
+/// Playback of preloaded animations, see `Service::preload`
+pub(crate) enum Playback {
+    Preload(CommandLib),
+    Show(usize),
+    /// The commands are encoded with another configuration from now on
+    Config(ImageConfig),
+}
+
+/// The latest frame the manager got
+enum Frame {
+    Command(Arc<Command>),
+    Image(RgbaImage, ImageConfig),
+    Library(usize),
+}
+
+impl Frame {
+    /// Split the frame into one update for each of `parts` clients, commands are encoded with
+    /// `config`
+    fn split(&self, parts: usize, config: ImageConfig) -> Vec<Update> {
+        match self {
+            Frame::Command(command) => partition(command, parts)
+                .into_iter()
+                .map(|command| Update::Command { command, config })
+                .collect(),
+            Frame::Image(image, config) => split_bands(image, *config, parts)
+                .into_iter()
+                .map(|(band, config)| Update::Image {
+                    width: band.width(),
+                    height: band.height(),
+                    rgba: band.into_raw(),
+                    config,
+                })
+                .collect(),
+            Frame::Library(frame) => (0..parts)
+                .map(|part| Update::Show {
+                    frame: *frame,
+                    part,
+                    parts,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Decode, Encode, Debug, Clone)]
 struct Target {
     addr: Vec<IpAddr>,
     port: u16,
     threads: usize,
+    protocol: Protocol,
+    name: String,
+    path: String,
 }
 
-fn read<R: Decode<()>>(stream: &mut TcpStream) -> Result<R, Box<dyn Error>> {
+/// Read a message of at most `limit` bytes from a blocking stream
+fn read<R: Decode<()>>(stream: &mut impl Read, limit: usize) -> Result<R, Box<dyn Error>> {
     let mut length = [0u8; 4];
     stream.read_exact(&mut length)?;
-    let length = u32::from_be_bytes(length);
-    let mut data = vec![0u8; length as usize];
+    let length = check_length(length, limit)?;
+    let mut data = vec![0u8; length];
     stream.read_exact(&mut data)?;
-    let decompressed = zstd::decode_all(&mut &data[..])?;
+    decode(&data, limit)
+}
+
+fn check_length(length: [u8; 4], limit: usize) -> Result<usize, Box<dyn Error>> {
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        Err(format!("Message of {length} bytes exceeds the limit of {limit} bytes"))?
+    }
+    Ok(length)
+}
+
+/// Decompress and decode a message, which may be at most `limit` bytes after decompression
+fn decode<R: Decode<()>>(data: &[u8], limit: usize) -> Result<R, Box<dyn Error>> {
+    let mut decompressed = Vec::new();
+    zstd::stream::Decoder::new(data)?
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > limit {
+        Err(format!("Message exceeds the limit of {limit} bytes after decompression"))?
+    }
     let (result, _) = bincode::decode_from_slice(&decompressed[..], standard())?;
     Ok(result)
 }
 
-// this write length encodes and ensures that everything or nothing is written
-fn write<S: Encode>(stream: &mut TcpStream, data: S) -> Result<(), Box<dyn Error>> {
+/// Collects messages from a stream that is non blocking or has a read timeout, so a message that
+/// is only partially received isn't lost
+struct Inbox {
+    buffer: Vec<u8>,
+    /// Largest message that is accepted, see `read`
+    limit: usize,
+    /// Number of bytes read so far
+    received: u64,
+}
+
+impl Inbox {
+    fn new(limit: usize) -> Inbox {
+        Inbox { buffer: Vec::new(), limit, received: 0 }
+    }
+
+    /// Read once, returns the next message once it is complete
+    /// Large messages take many calls, so the caller can do other things like sending heartbeats
+    /// in between
+    fn poll<R: Decode<()>>(&mut self, stream: &mut impl Read) -> Result<Option<R>, Box<dyn Error>> {
+        if let Some(message) = self.take()? {
+            return Ok(Some(message));
+        }
+        let mut chunk = [0u8; 64 * 1024];
+        match stream.read(&mut chunk) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            Ok(n) => {
+                self.buffer.extend_from_slice(&chunk[..n]);
+                self.received += n as u64;
+                self.take()
+            }
+            Err(ref e)
+                if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+
+    fn take<R: Decode<()>>(&mut self) -> Result<Option<R>, Box<dyn Error>> {
+        let Some(length) = self.buffer.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let end = 4 + check_length(*length, self.limit)?;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+        let message = decode(&self.buffer[4..end], self.limit);
+        self.buffer.drain(..end);
+        message.map(Some)
+    }
+}
+
+/// Compress `data` and prefix it with its length
+fn encode<S: Encode>(data: S) -> Result<Message, Box<dyn Error>> {
     let encoded = encode_to_vec(data, standard())?;
+    if encoded.len() > MESSAGE_LIMIT {
+        Err(format!("Message of {} bytes exceeds the limit of {MESSAGE_LIMIT} bytes", encoded.len()))?
+    }
     let compressed = zstd::encode_all(&encoded[..], 3)?;
-    let length = (compressed.len() as u32).to_be_bytes();
-    match stream.write(&length[..]) {
-        Ok(n) if n == 4 => {
-            let mut written = 0;
-            while written != compressed.len() {
-                match stream.write(&compressed[written..]) {
-                    Ok(n) => written += n,
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
-                    Err(e) => Err(e)?,
-                }
-            }
-            Ok(())
-        },
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
-        Err(e) => Err(e)?,
-        Ok(_) => panic!("Impossible to send less than 4 bytes"),
+    let mut message = Vec::with_capacity(4 + compressed.len());
+    message.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    message.extend_from_slice(&compressed);
+    Ok(message.into())
+}
+
+/// Encode an animation into `Update::Preload` messages of about `part_size` bytes of commands
+fn encode_preload(
+    library: CommandLib,
+    config: ImageConfig,
+    part_size: usize,
+) -> Result<Vec<Message>, Box<dyn Error>> {
+    let mut messages = Vec::new();
+    let mut frames = Vec::new();
+    let mut size = 0;
+    let count = library.len();
+    for (index, frame) in library.into_iter().enumerate() {
+        size += frame.command.iter().map(Vec::len).sum::<usize>();
+        frames.push(frame);
+        if size < part_size && index + 1 < count {
+            continue;
+        }
+        let frames = std::mem::take(&mut frames);
+        let first = messages.is_empty();
+        messages.push(encode(Update::Preload { frames, config, first })?);
+        size = 0;
+    }
+    Ok(messages)
+}
+
+/// Send a message over a blocking stream
+fn write<S: Encode>(stream: &mut impl Write, data: S) -> Result<(), Box<dyn Error>> {
+    stream.write_all(&encode(data)?)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// HMAC-SHA256 over both nonces, the label makes sure a tag can't be reflected to its sender
+fn sign(secret: &[u8], label: &[u8], first: &Nonce, second: &Nonce) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(label);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+fn check_version(version: u32) -> Result<(), Box<dyn Error>> {
+    if version != PROTOCOL_VERSION {
+        Err(format!("Peer speaks protocol version {version}, expected {PROTOCOL_VERSION}"))?
     }
+    Ok(())
+}
+
+/// Authenticate a connecting client
+fn server_handshake(
+    stream: &mut (impl Read + Write),
+    secret: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let nonce: Nonce = rand::random();
+    write(stream, Hello { version: PROTOCOL_VERSION, nonce })?;
+    let auth: Auth = read(stream, HANDSHAKE_LIMIT)?;
+    check_version(auth.version)?;
+    sign(secret, CLIENT_LABEL, &nonce, &auth.nonce)
+        .verify_slice(&auth.tag)
+        .map_err(|_| "Client does not know the secret")?;
+    let tag = sign(secret, SERVER_LABEL, &auth.nonce, &nonce).finalize().into_bytes().into();
+    write(stream, Welcome { tag })
+}
+
+/// Authenticate the manager
+fn client_handshake(
+    stream: &mut (impl Read + Write),
+    secret: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let hello: Hello = read(stream, HANDSHAKE_LIMIT)?;
+    check_version(hello.version)?;
+    let nonce: Nonce = rand::random();
+    let tag = sign(secret, CLIENT_LABEL, &hello.nonce, &nonce).finalize().into_bytes().into();
+    write(stream, Auth { version: PROTOCOL_VERSION, nonce, tag })?;
+    let welcome: Welcome = read(stream, HANDSHAKE_LIMIT)?;
+    sign(secret, SERVER_LABEL, &nonce, &hello.nonce)
+        .verify_slice(&welcome.tag)
+        .map_err(|_| "Manager does not know the secret")?;
+    Ok(())
 }
 
 impl Server {
     pub fn new(listen_port: u16, host: Host, threads: usize, data: Receiver<Arc<Command>>) -> Self {
+        let target = Target {
+            addr: host.addr,
+            port: host.port,
+            threads,
+            protocol: host.protocol,
+            name: host.name,
+            path: host.path,
+        };
         Server {
             listen_port,
-            host,
-            threads,
             clients: Vec::new(),
-            data,
+            status: Status::default(),
+            data: Some(data),
+            images: None,
+            playback: None,
+            library: Vec::new(),
+            config: ImageConfig::default(),
+            handshakes: Arc::default(),
+            acceptor: Acceptor {
+                target,
+                secret: Vec::new(),
+                #[cfg(feature = "tls")]
+                tls: None,
+            },
         }
     }
 
+    /// Send images from `images` to the clients instead of encoded commands, so every client
+    /// encodes its part of the image itself. This needs a lot less bandwidth
+    pub fn images(mut self, images: Receiver<(DynamicImage, ImageConfig)>) -> Self {
+        self.images = Some(images);
+        self
+    }
+
+    /// Receive preloaded animations and the frames to show from `playback`
+    pub(crate) fn playback(mut self, playback: Receiver<Playback>) -> Self {
+        self.playback = Some(playback);
+        self
+    }
+
+    /// The commands from `data` and `playback` are encoded with `config`, until `Playback::Config`
+    pub(crate) fn config(mut self, config: ImageConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Publish the reports of the clients to `status`
+    pub fn status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Only accept clients that know `secret`, which must not be empty
+    pub fn secret(mut self, secret: Vec<u8>) -> Self {
+        self.acceptor.secret = secret;
+        self
+    }
+
+    /// Encrypt the connections to all clients with TLS
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.acceptor.tls = Some(config);
+        self
+    }
+
+    /// Send every client its share of `frame`, dropping clients that can't be reached
+    fn distribute(&mut self, frame: &Frame) {
+        loop {
+            let count = self.clients.len();
+            for (client, part) in self.clients.iter_mut().zip(frame.split(count, self.config)) {
+                match encode(part) {
+                    Ok(message) => client.push(Outgoing { message, frame: true }),
+                    Err(err) => warn!("Unable to encode frame ({err})"),
+                }
+            }
+            if !self.send_all() {
+                break;
+            }
+            warn!("Lost {} client(s), rebalancing", count - self.clients.len());
+        }
+    }
+
+    /// Send an animation to all clients, and to every client that connects later on
+    fn preload(&mut self, library: CommandLib) {
+        let messages = match encode_preload(library, self.config, PRELOAD_PART) {
+            Ok(messages) => messages,
+            Err(err) => {
+                error!("Unable to send the animation to the clients ({err})");
+                return;
+            }
+        };
+        for client in &mut self.clients {
+            for message in &messages {
+                client.push(Outgoing { message: message.clone(), frame: false });
+            }
+        }
+        // sent once the sockets are writable
+        self.library = messages;
+    }
+
+    fn join(&mut self, mut client: Peer) {
+        for message in &self.library {
+            client.push(Outgoing { message: message.clone(), frame: false });
+        }
+        self.clients.push(client);
+        self.status.update(&self.clients);
+    }
+
+    /// Send the queues of all clients as far as possible, returns if any client was removed
+    fn send_all(&mut self) -> bool {
+        let count = self.clients.len();
+        self.clients.retain_mut(|client| client.send().is_ok());
+        self.clients.len() != count
+    }
+
+    /// Remove clients that stopped reporting, returns if any were removed
+    fn check_heartbeats(&mut self) -> bool {
+        let count = self.clients.len();
+        self.clients.retain(|client| {
+            let alive = client.last_seen.elapsed() <= HEARTBEAT_TIMEOUT;
+            if !alive {
+                warn!("Client {} stopped reporting", client.addr);
+            }
+            alive
+        });
+        self.status.update(&self.clients);
+        self.clients.len() != count
+    }
+
+    /// Move the inputs of the server to threads that wake up the event loop for every update
+    fn forward_inputs(&mut self, notifier: &Notifier) {
+        if let Some(data) = self.data.take() {
+            forward(data, notifier.clone(), |command| Event::Frame(Frame::Command(command)));
+        }
+        if let Some(images) = self.images.take() {
+            forward(images, notifier.clone(), |(image, config)| {
+                Event::Frame(Frame::Image(prepare_image(image, config), config))
+            });
+        }
+        if let Some(playback) = self.playback.take() {
+            forward(playback, notifier.clone(), |playback| match playback {
+                Playback::Preload(library) => Event::Preload(library),
+                Playback::Show(frame) => Event::Frame(Frame::Library(frame)),
+                Playback::Config(config) => Event::Config(config),
+            });
+        }
+    }
+
+    /// Accept all pending connections, and authenticate them on their own threads
+    fn accept_all(&self, listener: &TcpListener, notifier: &Notifier) {
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if self.handshakes.fetch_add(1, Ordering::Relaxed) >= MAX_HANDSHAKES {
+                        self.handshakes.fetch_sub(1, Ordering::Relaxed);
+                        // dropping the stream closes it right away
+                        warn!("Rejected client {addr} (too many pending handshakes)");
+                        continue;
+                    }
+                    let acceptor = self.acceptor.clone();
+                    let notifier = notifier.clone();
+                    let handshakes = self.handshakes.clone();
+                    spawn(move || {
+                        let result = acceptor.accept(stream);
+                        handshakes.fetch_sub(1, Ordering::Relaxed);
+                        match result {
+                            Ok(link) => {
+                                notifier.send(Event::Joined(Peer::new(link, addr)));
+                            }
+                            Err(err) => warn!("Rejected client {addr} ({err})"),
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("Unable to accept client ({err})");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Wait for sockets and inputs, and only send the latest frame to clients that are slow
+    /// NOTE: panics without a secret, see `Server::secret`
     pub fn listen(mut self) {
+        assert!(!self.acceptor.secret.is_empty(), "The manager needs a secret");
         let listener = TcpListener::bind(("0.0.0.0", self.listen_port)).expect("Server Error");
         listener.set_nonblocking(true).expect("Server Error");
+        let (waker, mut wakeup) = UnixStream::pair().expect("Server Error");
+        waker.set_nonblocking(true).expect("Server Error");
+        wakeup.set_nonblocking(true).expect("Server Error");
+        let (events_input, events) = channel();
+        let notifier = Notifier {
+            events: events_input,
+            waker: Arc::new(waker),
+        };
+        self.forward_inputs(&notifier);
+        let mut current: Option<Frame> = None;
+        let mut last_check = Instant::now();
         loop {
-            if let Ok((mut stream, _)) = listener.accept() {
-                let target = Target {
-                    addr: self.host.addr.clone(),
-                    port: self.host.port,
-                    threads: self.threads,
-                };
-                let _ = write(&mut stream, target);
-                stream.set_nonblocking(true).expect("Server Error");
-                self.clients.push(stream);
-            }
-            match self.data.try_recv() {
-                Ok(update) => {
-                    let mut to_remove = Vec::new();
-                    for (i, client) in self.clients.iter_mut().enumerate() {
-                        if let Err(_) = write(client, update.as_ref()) {
-                            to_remove.push(i);
-                        }
+            let timeout = HEARTBEAT_INTERVAL.saturating_sub(last_check.elapsed());
+            let ready = {
+                let mut fds = vec![
+                    PollFd::new(listener.as_fd(), PollFlags::POLLIN),
+                    PollFd::new(wakeup.as_fd(), PollFlags::POLLIN),
+                ];
+                fds.extend(self.clients.iter().map(|client| {
+                    PollFd::new(client.link.socket().as_fd(), client.interest())
+                }));
+                match poll(&mut fds, timeout.as_millis() as u16) {
+                    Ok(_) | Err(Errno::EINTR) => {}
+                    Err(err) => panic!("Server Error: {err}"),
+                }
+                fds.iter()
+                    .map(|fd| fd.revents().unwrap_or(PollFlags::empty()))
+                    .collect::<Vec<_>>()
+            };
+            let mut rebalance = false;
+            if !ready[0].is_empty() {
+                self.accept_all(&listener, &notifier);
+            }
+            if !ready[1].is_empty() {
+                while wakeup.read(&mut [0; 64]).is_ok_and(|n| n > 0) {}
+            }
+            // sockets first, the indices are only valid until clients are added or removed
+            let count = self.clients.len();
+            let mut index = 0;
+            self.clients.retain_mut(|client| {
+                let flags = ready[index + 2];
+                index += 1;
+                let readable = flags.intersects(
+                    PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR,
+                );
+                if readable && client.receive().is_err() {
+                    return false;
+                }
+                !flags.contains(PollFlags::POLLOUT) || client.send().is_ok()
+            });
+            if self.clients.len() != count {
+                warn!("Client disconnected, rebalancing");
+                self.status.update(&self.clients);
+                rebalance = true;
+            }
+            loop {
+                match events.try_recv() {
+                    Ok(Event::Frame(frame)) => {
+                        current = Some(frame);
+                        rebalance = true;
                     }
-                    to_remove.reverse();
-                    for to_remove in to_remove {
-                        self.clients.remove(to_remove);
+                    Ok(Event::Preload(library)) => self.preload(library),
+                    Ok(Event::Config(config)) => self.config = config,
+                    Ok(Event::Joined(client)) => {
+                        self.join(client);
+                        rebalance = true;
                     }
+                    Ok(Event::Stopped) => return,
+                    Err(_) => break,
+                }
+            }
+            if last_check.elapsed() >= HEARTBEAT_INTERVAL {
+                last_check = Instant::now();
+                if self.check_heartbeats() {
+                    warn!("Client disconnected, rebalancing");
+                    rebalance = true;
                 }
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => { break }
+            }
+            if let (true, Some(frame)) = (rebalance, &current) {
+                self.distribute(frame);
             }
         }
     }
 }
 
+/// Forward everything from `input` to the event loop, stop it once `input` is closed
+fn forward<T: Send + 'static>(
+    input: Receiver<T>,
+    notifier: Notifier,
+    event: impl Fn(T) -> Event + Send + 'static,
+) {
+    spawn(move || {
+        while let Ok(item) = input.recv() {
+            if !notifier.send(event(item)) {
+                return;
+            }
+        }
+        notifier.send(Event::Stopped);
+    });
+}
+
+impl Acceptor {
+    /// Authenticate a new client and tell it what to paint
+    fn accept(&self, stream: TcpStream) -> Result<Box<dyn Link>, Box<dyn Error>> {
+        // the listener is non blocking, which might be inherited
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let mut stream = tls::accept(config.clone(), stream)?;
+            server_handshake(&mut stream, &self.secret)?;
+            write(&mut stream, &self.target)?;
+            stream.sock.set_nonblocking(true)?;
+            return Ok(Box::new(stream));
+        }
+        let mut stream = stream;
+        server_handshake(&mut stream, &self.secret)?;
+        write(&mut stream, &self.target)?;
+        stream.set_nonblocking(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// How a client proves its identity to the manager and verifies the manager
+#[derive(Clone, Default)]
+pub struct Credentials {
+    /// Shared secret of manager and clients, must not be empty
+    pub secret: Vec<u8>,
+    /// SHA-256 fingerprint of the manager certificate, enables TLS
+    #[cfg(feature = "tls")]
+    pub pin: Option<Pin>,
+}
+
 pub struct Client {
     mod_host: Host,
     pub target_host: Host,
     pub threads: usize,
+    credentials: Credentials,
+    /// Connection the target was read from, kept so the manager doesn't see a short lived client
+    stream: Box<dyn Link>,
 }
 
 impl Client {
-    pub fn new(mod_host: Host, bind_addr: Option<String>) -> Result<Self, Box<dyn Error>> {
-        let def: Target = read(&mut mod_host.new_stream()?)?;
+    /// Connect to a manager that knows `secret`
+    pub fn new(
+        mod_host: Host,
+        bind_addr: Option<String>,
+        secret: Vec<u8>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let credentials = Credentials {
+            secret,
+            #[cfg(feature = "tls")]
+            pin: None,
+        };
+        Self::with_credentials(mod_host, bind_addr, credentials)
+    }
+
+    /// Connect to a manager over TLS, only trusting the certificate with the SHA-256 fingerprint
+    /// `pin`. This protects against rogue managers on the network
+    #[cfg(feature = "tls")]
+    pub fn new_pinned(
+        mod_host: Host,
+        bind_addr: Option<String>,
+        secret: Vec<u8>,
+        pin: Pin,
+    ) -> Result<Self, Box<dyn Error>> {
+        let credentials = Credentials {
+            secret,
+            pin: Some(pin),
+        };
+        Self::with_credentials(mod_host, bind_addr, credentials)
+    }
+
+    /// Connect to a manager, authenticating both sides with `credentials`
+    pub fn with_credentials(
+        mod_host: Host,
+        bind_addr: Option<String>,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn Error>> {
+        if credentials.secret.is_empty() {
+            Err("The secret of the manager must not be empty")?
+        }
+        let (stream, def) = Self::connect(&mod_host, &credentials)?;
         println!("Connected to server, def:{:?}", def);
+        let target_host = Host {
+            protocol: def.protocol,
+            name: def.name,
+            path: def.path,
+            ..Host::from_raw(def.addr, def.port, bind_addr)?
+        };
         Ok(Client {
             mod_host,
-            target_host: Host::from_raw(def.addr, def.port, bind_addr)?,
+            target_host,
             threads: def.threads,
+            credentials,
+            stream,
         })
     }
 
+    /// Connect to the manager, complete the handshake and read the target
+    /// Reads on the returned stream time out after `HEARTBEAT_INTERVAL`, so reports can be sent
+    /// while waiting for updates
+    fn connect(
+        mod_host: &Host,
+        credentials: &Credentials,
+    ) -> Result<(Box<dyn Link>, Target), Box<dyn Error>> {
+        let stream = mod_host.new_stream()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let secret = &credentials.secret;
+        #[cfg(feature = "tls")]
+        if let Some(pin) = credentials.pin {
+            let mut stream = tls::connect_pinned(&mod_host.name, stream, pin)?;
+            client_handshake(&mut stream, secret)?;
+            let target = read(&mut stream, HANDSHAKE_LIMIT)?;
+            stream.sock.set_read_timeout(Some(HEARTBEAT_INTERVAL))?;
+            return Ok((Box::new(stream), target));
+        }
+        let mut stream = stream;
+        client_handshake(&mut stream, secret)?;
+        let target = read(&mut stream, HANDSHAKE_LIMIT)?;
+        stream.set_read_timeout(Some(HEARTBEAT_INTERVAL))?;
+        Ok((Box::new(stream), target))
+    }
+
     pub fn start(self) -> impl FnMut(&mut Service) {
-        let mut stream = self.mod_host.new_stream().expect("Server Error");
-        let _: Target = read(&mut stream).expect("Server Error");
+        let Client { mod_host, credentials, mut stream, .. } = self;
+        let mut state = WorkerState::default();
+        let mut inbox = Inbox::new(MESSAGE_LIMIT);
+        let mut last_report: Option<Instant> = None;
         move |service: &mut Service | {
-            if let Ok(data) = read(&mut stream) {
-                let arced: Arc<Command> = Arc::new(data);
-                let _ = service.painter_input.as_ref().unwrap().try_send(arced);
-            } else {
-                warn!("Connection to manager lost, reconnecting");
-                sleep(Duration::from_secs(1));
-                // using return here ensures that the process can be exited after at most 1s
-                let Ok(new_stream) = self.mod_host.new_stream() else { return };
-                stream = new_stream;
-                let Ok(_) = read::<Target>(&mut stream) else { return };
-                let Ok(command) = read::<Command>(&mut stream) else { return };
-                let _ = service.painter_input.as_ref().unwrap().try_send(Arc::new(command));
-                warn!("Reconnected to manager");
+            let mut result = Ok(None);
+            if last_report.is_none_or(|last| last.elapsed() >= HEARTBEAT_INTERVAL) {
+                last_report = Some(Instant::now());
+                result = write(&mut stream, Report { stats: service.stats() }).map(|_| None);
+            }
+            if result.is_ok() {
+                result = inbox.poll(&mut stream);
+            }
+            match result {
+                Ok(Some(update)) => state.apply(service, update),
+                Ok(None) => {}
+                Err(_) => {
+                    warn!("Connection to manager lost, reconnecting");
+                    sleep(Duration::from_secs(1));
+                    // using return here ensures that the process can be exited after at most 1s
+                    let Ok((new_stream, _)) = Self::connect(&mod_host, &credentials) else { return };
+                    stream = new_stream;
+                    inbox = Inbox::new(MESSAGE_LIMIT);
+                    last_report = None;
+                    warn!("Reconnected to manager");
+                }
             }
         }
     }
 }
+
+/// Everything a client needs to remember to understand the updates of the manager
+#[derive(Default)]
+struct WorkerState {
+    image_config: Option<ImageConfig>,
+    /// Animation preloaded by the manager
+    library: CommandLib,
+}
+
+impl WorkerState {
+    /// Hand an update of the manager to the service
+    fn apply(&mut self, service: &mut Service, update: Update) {
+        let command = match update {
+            Update::Command { command, config } => service.fit_to_host(Arc::new(command), &config),
+            Update::Image { width, height, rgba, config } => {
+                let Some(image) = RgbaImage::from_raw(width, height, rgba) else {
+                    warn!("Manager sent a malformed image");
+                    return;
+                };
+                if self.image_config != Some(config) {
+                    service.change_image_config(config);
+                    self.image_config = Some(config);
+                }
+                service.send_image(DynamicImage::ImageRgba8(image));
+                return;
+            }
+            Update::Preload { frames, config, first } => {
+                if frames.is_empty() {
+                    warn!("Manager preloaded an animation without frames");
+                    return;
+                }
+                if first {
+                    self.library.clear();
+                }
+                self.library.extend(frames.into_iter().map(|frame| TimedCommand {
+                    command: service.fit_to_host(frame.command, &config),
+                    ..frame
+                }));
+                return;
+            }
+            Update::Show { frame, part, parts } => {
+                let Some(frame) = self.library.get(frame) else {
+                    warn!("Manager showed frame {frame}, which was never preloaded");
+                    return;
+                };
+                let command = share(&frame.command, part, parts);
+                // keep painting the previous frame if nothing changed in this share
+                if is_idle(&command) {
+                    return;
+                }
+                Arc::new(command)
+            }
+        };
+        let _ = service.painter_input.as_ref().unwrap().try_send(command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(
+        server_secret: &'static [u8],
+        client_secret: &[u8],
+    ) -> (Result<(), String>, Result<(), String>) {
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        let server = spawn(move || {
+            let result = server_handshake(&mut server, server_secret).map_err(|e| e.to_string());
+            // the client fails to read the welcome message once the server is gone
+            drop(server);
+            result
+        });
+        let client = client_handshake(&mut client, client_secret).map_err(|e| e.to_string());
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (server, client) = handshake(b"secret", b"secret");
+        assert_eq!(server, Ok(()));
+        assert_eq!(client, Ok(()));
+    }
+
+    #[test]
+    fn test_handshake_wrong_secret() {
+        let (server, client) = handshake(b"secret", b"other");
+        assert_eq!(server, Err("Client does not know the secret".to_string()));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn test_handshake_version_mismatch() {
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        let hello = Hello { version: PROTOCOL_VERSION + 1, nonce: [0; 32] };
+        write(&mut server, hello).unwrap();
+        let err = client_handshake(&mut client, b"secret").unwrap_err();
+        assert!(err.to_string().contains("protocol version"));
+    }
+
+    #[test]
+    fn test_inbox_reads_once() {
+        let data: Vec<u8> = (0..200_000).map(|_| rand::random()).collect();
+        let message = encode(data.clone()).unwrap();
+        assert!(message.len() > 64 * 1024);
+        let mut stream = &message[..];
+        let mut inbox = Inbox::new(MESSAGE_LIMIT);
+        // the message arrives over multiple calls, every one of them reads
+        assert!(inbox.poll::<Vec<u8>>(&mut stream).unwrap().is_none());
+        assert_eq!(inbox.received, 64 * 1024);
+        let received = loop {
+            if let Some(received) = inbox.poll::<Vec<u8>>(&mut stream).unwrap() {
+                break received;
+            }
+        };
+        assert_eq!(received, data);
+        assert_eq!(inbox.received, message.len() as u64);
+    }
+
+    #[test]
+    fn test_message_limit() {
+        // the length is checked before anything is read
+        let length = 1_000_000u32.to_be_bytes();
+        let err = read::<Vec<u8>>(&mut &length[..], HANDSHAKE_LIMIT).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+        let message = encode(vec![0u8; 64 * 1024]).unwrap();
+        // zeros compress well, so the limit also has to hold after decompression
+        assert!(message.len() < HANDSHAKE_LIMIT);
+        let err = read::<Vec<u8>>(&mut &message[..], HANDSHAKE_LIMIT).unwrap_err();
+        assert!(err.to_string().contains("after decompression"));
+        let mut inbox = Inbox::new(HANDSHAKE_LIMIT);
+        assert!(inbox.poll::<Vec<u8>>(&mut &message[..]).is_err());
+        let data: Vec<u8> = read(&mut &message[..], MESSAGE_LIMIT).unwrap();
+        assert_eq!(data.len(), 64 * 1024);
+    }
+
+    #[test]
+    fn test_preload_parts() {
+        let frame = TimedCommand::new(vec![vec![0u8; 10]], None);
+        let messages = encode_preload(vec![frame; 5], ImageConfig::default(), 25).unwrap();
+        let parts: Vec<_> = messages
+            .iter()
+            .map(|message| match read(&mut &message[..], MESSAGE_LIMIT).unwrap() {
+                Update::Preload { frames, first, .. } => (frames.len(), first),
+                _ => panic!("Expected a preload"),
+            })
+            .collect();
+        assert_eq!(parts, vec![(3, true), (2, false)]);
+    }
+}
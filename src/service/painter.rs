@@ -1,4 +1,5 @@
 use std::{
+    net::TcpStream,
     sync::{
         mpsc::{Receiver, TryRecvError},
         Arc,
@@ -9,32 +10,70 @@ use std::{
 
 use log::warn;
 
-use crate::{image_handler::Command, painter, Client};
+#[cfg(target_os = "linux")]
+use crate::ZeroCopyClient;
+use crate::{
+    image_handler::Command,
+    painter, Client, Slot,
+};
 
-use super::Host;
+use super::{
+    stats::{Counted, Stats},
+    udp::UdpSink,
+    Host, Protocol,
+};
 
 pub fn get_painter(
     source: Receiver<Arc<Command>>,
     host: Host,
-    painter_id: usize,
-    max_frame: usize,
+    slot: Arc<Slot>,
+    zero_copy: bool,
+    stats: Arc<Stats>,
 ) -> impl FnMut() {
     move || {
-        let mut current_commands = source.recv().unwrap();
+        // the painter was removed before it got its first command
+        let Ok(mut current_commands) = source.recv() else {
+            return;
+        };
         loop {
-            match host.new_stream() {
-                Ok(stream) => {
-                    let client = Client::new(stream);
-                    current_commands =
-                        painter(&source, client, painter_id, max_frame, current_commands);
+            let result = match host.protocol {
+                Protocol::Tcp if zero_copy => host.new_stream().map(|stream| {
+                    let commands = current_commands.clone();
+                    let stats = stats.clone();
+                    paint_zero_copy(&source, stream, &slot, commands, stats)
+                }),
+                Protocol::Tcp | Protocol::WebSocket | Protocol::Tls => host.new_connection().map(|connection| {
+                    let client = Counted::new(Client::new(connection), stats.clone());
+                    painter(
+                        &source,
+                        client,
+                        &slot,
+                        current_commands.clone(),
+                    )
+                }),
+                Protocol::Udp => host.new_udp_socket().map(|socket| {
+                    let sink = Counted::new(UdpSink::new(socket), stats.clone());
+                    painter(
+                        &source,
+                        sink,
+                        &slot,
+                        current_commands.clone(),
+                    )
+                }),
+            };
+            match result {
+                Ok(commands) => {
+                    current_commands = commands;
                     // this might discard an animation frame, if the connection is dropped, and it is an
                     // ongoing animation, but that is mostly irrelevant, since there will be new
                     // frames later, and the connection is timed out anyway
                     if let Err(TryRecvError::Disconnected) = source.try_recv() {
                         break;
                     }
+                    stats.add_reconnect();
                 }
                 Err(err) => {
+                    stats.add_error();
                     warn!("Could not connect to host! ({err:?})");
                 }
             }
@@ -43,3 +82,44 @@ pub fn get_painter(
         }
     }
 }
+
+#[cfg(target_os = "linux")]
+fn paint_zero_copy(
+    source: &Receiver<Arc<Command>>,
+    stream: TcpStream,
+    slot: &Slot,
+    current_commands: Arc<Command>,
+    stats: Arc<Stats>,
+) -> Arc<Command> {
+    match ZeroCopyClient::try_new(stream) {
+        Ok(client) => {
+            let client = Counted::new(client, stats);
+            painter(source, client, slot, current_commands)
+        }
+        Err((err, stream)) => {
+            warn!("Zero copy not available, falling back to copying ({err:?})");
+            painter(
+                source,
+                Counted::new(Client::new(stream), stats),
+                slot,
+                current_commands,
+            )
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn paint_zero_copy(
+    source: &Receiver<Arc<Command>>,
+    stream: TcpStream,
+    slot: &Slot,
+    current_commands: Arc<Command>,
+    stats: Arc<Stats>,
+) -> Arc<Command> {
+    painter(
+        source,
+        Counted::new(Client::new(stream), stats),
+        slot,
+        current_commands,
+    )
+}
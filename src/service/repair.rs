@@ -0,0 +1,244 @@
+use std::{
+    io,
+    sync::{
+        mpsc::{Receiver, SyncSender, TryRecvError, TrySendError},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::Duration,
+};
+
+use image::{DynamicImage, Pixel, Rgb, Rgba, RgbaImage};
+use log::{error, info, warn};
+
+use crate::{
+    image_handler::{prepare_image, rgba_to_commands, Command, ImageConfig},
+    Client, Transport,
+};
+
+use super::{Host, Protocol};
+
+/// Number of pixels read from the canvas per round trip
+const READ_BATCH: usize = 4096;
+/// Number of connections the canvas is read over in parallel
+const READ_CONNECTIONS: usize = 4;
+/// Pause between two comparisons of the canvas
+const REPAIR_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum RepairChange {
+    Config(ImageConfig),
+    Image(DynamicImage),
+}
+
+/// Colour a pixel of the target has on the canvas once it is painted over `canvas`
+/// With alpha usage, the server blends semi transparent pixels onto the canvas, otherwise they
+/// are painted opaque
+fn expected_color(pixel: Rgba<u8>, canvas: Rgb<u8>, config: ImageConfig) -> Rgb<u8> {
+    let alpha = pixel.0[3] as u32;
+    if !config.alpha_usage || alpha == 255 {
+        return pixel.to_rgb();
+    }
+    Rgb(std::array::from_fn(|i| {
+        ((pixel.0[i] as u32 * alpha + canvas.0[i] as u32 * (255 - alpha) + 127) / 255) as u8
+    }))
+}
+
+/// If painting `pixel` again wouldn't change the canvas, allowing for rounding differences of
+/// the server
+fn is_painted(pixel: Rgba<u8>, canvas: Rgb<u8>, config: ImageConfig) -> bool {
+    let expected = expected_color(pixel, canvas, config);
+    expected
+        .0
+        .iter()
+        .zip(canvas.0)
+        .all(|(expected, current)| expected.abs_diff(current) <= 1)
+}
+
+/// Read the canvas below the target and return a copy of the target where all pixels that are
+/// already correct are transparent, together with the number of wrong pixels
+/// Every client reads its own part of the canvas, all at the same time
+fn diff_canvas<T: Transport + Send>(
+    clients: &mut [Client<T>],
+    target: &RgbaImage,
+    config: ImageConfig,
+) -> io::Result<(RgbaImage, usize)> {
+    let pixels: Vec<_> = target
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[3] != 0)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+    let share = pixels.len().div_ceil(clients.len()).max(1);
+    let canvas = thread::scope(|scope| {
+        let readers: Vec<_> = clients
+            .iter_mut()
+            .zip(pixels.chunks(share))
+            .map(|(client, pixels)| scope.spawn(move || read_canvas(client, pixels, config)))
+            .collect();
+        readers
+            .into_iter()
+            .map(|reader| reader.join().expect("Canvas reader panicked"))
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+    let mut diff = target.clone();
+    let mut wrong = 0;
+    for ((x, y), current) in pixels.iter().zip(canvas.into_iter().flatten()) {
+        let pixel = diff.get_pixel_mut(*x, *y);
+        if is_painted(*pixel, current, config) {
+            pixel.0[3] = 0;
+        } else {
+            wrong += 1;
+        }
+    }
+    Ok((diff, wrong))
+}
+
+/// Read the colour of the canvas below `pixels` of the target, `READ_BATCH` pixels per round trip
+fn read_canvas<T: Transport>(
+    client: &mut Client<T>,
+    pixels: &[(u32, u32)],
+    config: ImageConfig,
+) -> io::Result<Vec<Rgb<u8>>> {
+    let mut canvas = Vec::with_capacity(pixels.len());
+    for batch in pixels.chunks(READ_BATCH) {
+        let positions: Vec<_> = batch
+            .iter()
+            .map(|(x, y)| (x + config.x_offset, y + config.y_offset))
+            .collect();
+        canvas.extend(client.read_pixel_multi(&positions)?);
+    }
+    Ok(canvas)
+}
+
+/// Repeatedly compares the canvas with the target image and only paints the pixels that differ
+/// NOTE: the canvas is read with `PX x y` commands over `READ_CONNECTIONS` additional
+/// connections, so this does not work over UDP
+pub fn get_repairer(
+    source: Receiver<RepairChange>,
+    sink: SyncSender<Arc<Command>>,
+    host: Host,
+    mut image_config: ImageConfig,
+) -> impl FnMut() {
+    move || {
+        if host.protocol == Protocol::Udp {
+            error!("Unable to repair the canvas, UDP servers can't be read");
+            return;
+        }
+        let mut clients: Vec<Client> = Vec::new();
+        let mut image: Option<DynamicImage> = None;
+        let mut target: Option<RgbaImage> = None;
+        // the painters were told that the canvas matches the image
+        let mut idle = false;
+        loop {
+            let mut changed = false;
+            loop {
+                // Waits for first image
+                let change = if image.is_none() {
+                    source.recv().map_err(|_| TryRecvError::Disconnected)
+                } else {
+                    source.try_recv()
+                };
+                match change {
+                    Ok(RepairChange::Config(config)) => image_config = config,
+                    Ok(RepairChange::Image(new_image)) => image = Some(new_image),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+                changed = true;
+            }
+            if let (true, Some(image)) = (changed, &image) {
+                let prepared = prepare_image(image.clone(), image_config);
+                // paint everything until the canvas has been compared
+                let command = Arc::new(rgba_to_commands(&prepared, image_config));
+                if let Err(TrySendError::Disconnected(_)) = sink.try_send(command) {
+                    break;
+                }
+                target = Some(prepared);
+                idle = false;
+            }
+            let Some(target) = &target else {
+                continue;
+            };
+            if clients.is_empty() {
+                let connections: io::Result<Vec<_>> = (0..READ_CONNECTIONS)
+                    .map(|_| host.new_connection())
+                    .collect();
+                match connections {
+                    Ok(connections) => clients = connections.into_iter().map(Client::new).collect(),
+                    Err(err) => {
+                        warn!("Could not connect to host! ({err:?})");
+                        sleep(Duration::from_secs(5));
+                        continue;
+                    }
+                }
+            }
+            match diff_canvas(&mut clients, target, image_config) {
+                Ok((_, 0)) if idle => {}
+                Ok((_, 0)) => {
+                    // stops the painters from repainting the last diff
+                    match sink.try_send(Arc::new(Command::new())) {
+                        Ok(()) => idle = true,
+                        Err(TrySendError::Full(_)) => {}
+                        Err(TrySendError::Disconnected(_)) => break,
+                    }
+                }
+                Ok((diff, wrong)) => {
+                    info!("Repairing {wrong} pixels");
+                    let command = Arc::new(rgba_to_commands(&diff, image_config));
+                    match sink.try_send(command) {
+                        Ok(()) => idle = false,
+                        Err(TrySendError::Full(_)) => {}
+                        Err(TrySendError::Disconnected(_)) => break,
+                    }
+                }
+                Err(err) => {
+                    warn!("Lost connection while reading the canvas ({err:?})");
+                    clients.clear();
+                }
+            }
+            sleep(REPAIR_INTERVAL)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_handler::ImageConfigBuilder;
+    use image::ImageBuffer;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    /// Answer `PX x y` reads with a white canvas left of `x = 2`, and a black one elsewhere
+    fn canvas_server(stream: UnixStream) {
+        let mut writer = stream.try_clone().unwrap();
+        for line in BufReader::new(stream).lines() {
+            let line = line.unwrap();
+            let mut words = line.split(' ').skip(1);
+            let x: u32 = words.next().unwrap().parse().unwrap();
+            let y: u32 = words.next().unwrap().parse().unwrap();
+            let color = if x < 2 { "ffffff" } else { "000000" };
+            writeln!(writer, "PX {x} {y} {color}").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_diff_canvas() {
+        let mut clients: Vec<_> = (0..3)
+            .map(|_| {
+                let (client, server) = UnixStream::pair().unwrap();
+                thread::spawn(move || canvas_server(server));
+                Client::new(client)
+            })
+            .collect();
+        // white everywhere, except a transparent pixel that is never read
+        let mut target: RgbaImage = ImageBuffer::from_pixel(4, 3, Rgba([255, 255, 255, 255]));
+        target.put_pixel(3, 0, Rgba([0, 0, 0, 0]));
+        let config = ImageConfigBuilder::new().x_offset(1).build();
+        let (diff, wrong) = diff_canvas(&mut clients, &target, config).unwrap();
+        // with the offset, only the pixels at x = 0 are on the white part of the canvas
+        assert_eq!(wrong, 3 * 3 - 1);
+        for (x, y, pixel) in diff.enumerate_pixels() {
+            assert_eq!(pixel.0[3] == 0, x == 0 || (x, y) == (3, 0));
+        }
+    }
+}
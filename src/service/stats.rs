@@ -0,0 +1,148 @@
+use std::{
+    io::Result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bincode::{Decode, Encode};
+
+use crate::{image_handler::Command, CommandSink};
+
+/// Counters of a painter
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Bytes handed to the connections
+    pub bytes: AtomicU64,
+    /// How often the painter sent all of its chunks
+    pub frames: AtomicU64,
+    /// How often painters had to reconnect
+    pub reconnects: AtomicU64,
+    /// Failed connection attempts and writes
+    pub errors: AtomicU64,
+    /// Currently open connections to the pixelflut server, at most one per painter
+    pub connections: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_frame(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn open_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn close_connection(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Copy the current values
+    pub fn snapshot(&self, painters: usize) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            painters,
+        }
+    }
+}
+
+/// The values of `Stats` at one point in time
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct StatsSnapshot {
+    pub bytes: u64,
+    pub frames: u64,
+    pub reconnects: u64,
+    pub errors: u64,
+    pub connections: u64,
+    /// Number of painters
+    pub painters: usize,
+}
+
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} painter(s), {} connection(s), {} MiB sent, {} frame(s), {} reconnect(s), {} error(s)",
+            self.painters,
+            self.connections,
+            self.bytes / (1024 * 1024),
+            self.frames,
+            self.reconnects,
+            self.errors,
+        )
+    }
+}
+
+impl std::ops::Add for StatsSnapshot {
+    type Output = StatsSnapshot;
+
+    fn add(self, other: StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes: self.bytes + other.bytes,
+            frames: self.frames + other.frames,
+            reconnects: self.reconnects + other.reconnects,
+            errors: self.errors + other.errors,
+            connections: self.connections + other.connections,
+            painters: self.painters + other.painters,
+        }
+    }
+}
+
+impl std::iter::Sum for StatsSnapshot {
+    fn sum<I: Iterator<Item = StatsSnapshot>>(iter: I) -> StatsSnapshot {
+        iter.fold(StatsSnapshot::default(), |a, b| a + b)
+    }
+}
+
+/// Counts everything that is sent through the wrapped sink, and the sink as an open connection
+/// while it exists
+pub(crate) struct Counted<S> {
+    sink: S,
+    stats: Arc<Stats>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(sink: S, stats: Arc<Stats>) -> Counted<S> {
+        stats.open_connection();
+        Counted { sink, stats }
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.stats.close_connection();
+    }
+}
+
+impl<S: CommandSink> CommandSink for Counted<S> {
+    fn send_chunk(&mut self, commands: &Arc<Command>, chunk: usize) -> Result<()> {
+        let result = self.sink.send_chunk(commands, chunk);
+        match result {
+            Ok(()) => self.stats.add_bytes(commands[chunk].len()),
+            Err(_) => self.stats.add_error(),
+        }
+        result
+    }
+
+    fn frame_done(&mut self) {
+        self.stats.add_frame();
+        self.sink.frame_done();
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    io::{ErrorKind, Result},
+    net::UdpSocket,
+    sync::Arc,
+};
+
+use crate::{image_handler::Command, CommandSink};
+
+/// Largest datagram payload that doesn't get fragmented with a 1500 byte MTU, even over IPv6
+pub const DATAGRAM_SIZE: usize = 1452;
+
+/// Sends every chunk of a command as a single datagram
+/// NOTE: the commands have to be encoded with `ImageConfig::datagram_size`, so chunks end on pixel
+/// boundaries and fit into a datagram. `Service` takes care of this for UDP hosts
+pub struct UdpSink {
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    pub fn new(socket: UdpSocket) -> UdpSink {
+        UdpSink { socket }
+    }
+}
+
+impl CommandSink for UdpSink {
+    fn send_chunk(&mut self, commands: &Arc<Command>, chunk: usize) -> Result<()> {
+        match self.socket.send(&commands[chunk]) {
+            Ok(_) => Ok(()),
+            // the send buffer is full, this datagram is lost just like on the network
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(err) if err.raw_os_error() == Some(nix::libc::ENOBUFS) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
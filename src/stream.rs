@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::Child,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver},
+        Arc,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::Duration,
+};
+
+use image::DynamicImage;
+use pixelbomber::{
+    decoder,
+    image_handler::{
+        delta_to_commands, image_to_commands, is_idle, Command, ImageConfig, TimedCommand,
+    },
+    service::Service,
+};
+
+use crate::control::Source;
+use crate::manager::{
+    duration_between, encode_bmp, frame_interval, read_ffmpeg_log, spawn_ffmpeg, BitmapReader,
+    FileInput, Schedule, IDLE_INTERVAL,
+};
+
+/// How long to wait for the timestamp of a frame in the log of ffmpeg
+const LOG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// One pass through the input, with the duration of every frame if it is known
+pub type Pass<T> = Box<dyn Iterator<Item = (T, Option<Duration>)>>;
+
+/// Load a video or animation that is decoded while playing, instead of all at once
+pub fn load(
+    input: &FileInput,
+    paths: &[String],
+    config: ImageConfig,
+    fps: f32,
+    buffer: usize,
+) -> Result<Source, String> {
+    let [path] = paths else {
+        return Err("--stream only works with exactly one input file".to_string());
+    };
+    let path = path.clone();
+    let animated = Path::new(&path).is_file()
+        && decoder::open(Path::new(&path))
+            .map_err(|err| format!("Unable to load {path} ({err})"))?
+            .is_some();
+    let frames = if animated {
+        stream(
+            move || open_animation(&path),
+            move |image: DynamicImage, previous| {
+                Some(match previous {
+                    Some(previous) => delta_to_commands(previous, image, config),
+                    None => image_to_commands(image, config),
+                })
+            },
+            input.workers,
+            input.keyframe_interval,
+            buffer,
+        )
+    } else if input.video {
+        stream(
+            move || open_video(&path),
+            move |image, previous| encode_bmp(image, previous, config),
+            input.workers,
+            input.keyframe_interval,
+            buffer,
+        )
+    } else {
+        return Err("--stream only works with animations and videos".to_string());
+    };
+    Ok(Box::new(manage_stream(frames, fps)))
+}
+
+fn open_animation(path: &str) -> Result<Pass<DynamicImage>, String> {
+    let frames = decoder::open(Path::new(path))
+        .map_err(|err| format!("Unable to load {path} ({err})"))?
+        .ok_or_else(|| format!("{path} is no animation"))?;
+    let path = path.to_string();
+    Ok(Box::new(frames.map_while(move |frame| match frame {
+        Ok(frame) => Some((frame.image, Some(frame.delay))),
+        Err(err) => {
+            println!("Unable to decode {path} ({err})");
+            None
+        }
+    })))
+}
+
+fn open_video(path: &str) -> Result<Pass<Vec<u8>>, String> {
+    let mut child =
+        spawn_ffmpeg(path).map_err(|err| format!("Unable to execute ffmpeg ({err})"))?;
+    let (timestamps, timestamp_output) = channel();
+    let log = read_ffmpeg_log(child.stderr.take().unwrap(), timestamps);
+    let reader = BitmapReader::new(child.stdout.take().unwrap());
+    let mut timestamps = Timestamps {
+        output: timestamp_output,
+        pending: HashMap::new(),
+    };
+    Ok(Box::new(VideoPass {
+        child,
+        log: Some(log),
+        frames: Box::new(
+            reader
+                .zip(0..)
+                .map(move |(bmp, frame)| (bmp, timestamps.get(frame))),
+        ),
+        current: None,
+        duration: None,
+        sent: false,
+    }))
+}
+
+/// Timestamps of the frames of a running ffmpeg process, as logged by the showinfo filter
+struct Timestamps {
+    output: Receiver<(u64, f64)>,
+    /// Timestamps of frames that were logged before they were read
+    pending: HashMap<u64, f64>,
+}
+
+impl Timestamps {
+    /// The timestamp of `frame`, waits up to `LOG_TIMEOUT` for it to be logged. `None` if it is
+    /// missing from the log
+    fn get(&mut self, frame: u64) -> Option<f64> {
+        self.pending.retain(|&logged, _| logged >= frame);
+        self.pending.extend(self.output.try_iter());
+        loop {
+            if let Some(timestamp) = self.pending.remove(&frame) {
+                return Some(timestamp);
+            }
+            // later frames were logged already, so this one won't be
+            if self.pending.keys().any(|&logged| logged > frame) {
+                return None;
+            }
+            let (logged, timestamp) = self.output.recv_timeout(LOG_TIMEOUT).ok()?;
+            self.pending.insert(logged, timestamp);
+        }
+    }
+}
+
+/// Frames of a running ffmpeg process. A frame is only passed on once the timestamp of the next
+/// one is known, to tell how long it is shown
+struct VideoPass {
+    child: Child,
+    log: Option<JoinHandle<String>>,
+    frames: Box<dyn Iterator<Item = (Vec<u8>, Option<f64>)>>,
+    current: Option<(Vec<u8>, Option<f64>)>,
+    /// Duration of the previous frame, the last frame is shown as long
+    duration: Option<Duration>,
+    sent: bool,
+}
+
+impl Iterator for VideoPass {
+    type Item = (Vec<u8>, Option<Duration>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = self.frames.next();
+            let current = std::mem::replace(&mut self.current, next);
+            match (current, &self.current) {
+                (None, Some(_)) => continue,
+                (Some((bmp, start)), Some((_, end))) => {
+                    self.duration = start
+                        .zip(*end)
+                        .and_then(|(start, end)| duration_between(start, end));
+                    self.sent = true;
+                    return Some((bmp, self.duration));
+                }
+                (Some((bmp, _)), None) => {
+                    self.sent = true;
+                    return Some((bmp, self.duration));
+                }
+                (None, None) => {
+                    let _ = self.child.wait();
+                    if let Some(log) = self.log.take() {
+                        let error = log.join().expect("Unable to read the log of ffmpeg");
+                        if !self.sent {
+                            print!("ffmpeg error:\n{}", error);
+                        }
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for VideoPass {
+    fn drop(&mut self) {
+        // playback stopped in the middle of the video
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Decode and encode frames on background threads while they are played, with at most `buffer`
+/// encoded frames waiting. `open` is called again at the end of the input, to loop it
+pub fn stream<T: Clone + Send + 'static>(
+    mut open: impl FnMut() -> Result<Pass<T>, String> + Send + 'static,
+    encode: impl Fn(T, Option<T>) -> Option<Command> + Clone + Send + 'static,
+    workers: usize,
+    keyframe_interval: Option<usize>,
+    buffer: usize,
+) -> Receiver<TimedCommand> {
+    let mut worker_txs = Vec::with_capacity(workers);
+    let mut worker_rxs = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let encode = encode.clone();
+        let (worker_tx, worker_input) = sync_channel::<(T, Option<T>, Option<Duration>)>(1);
+        let (worker_output, worker_rx) = sync_channel(1);
+        worker_txs.push(worker_tx);
+        worker_rxs.push(worker_rx);
+        thread::spawn(move || {
+            while let Ok((image, previous, duration)) = worker_input.recv() {
+                let command = encode(image, previous).map(|command| TimedCommand {
+                    command: Arc::new(command),
+                    duration,
+                });
+                if worker_output.send(command).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    thread::spawn(move || {
+        let mut worker = 0;
+        loop {
+            let pass = match open() {
+                Ok(pass) => pass,
+                Err(err) => {
+                    println!("{err}");
+                    return;
+                }
+            };
+            // every pass starts with a keyframe
+            let mut frame: usize = 0;
+            let mut previous: Option<T> = None;
+            for (image, duration) in pass {
+                let is_keyframe =
+                    keyframe_interval.is_none_or(|interval| frame.is_multiple_of(interval));
+                let delta_base = if is_keyframe { None } else { previous.take() };
+                if keyframe_interval.is_some() {
+                    previous = Some(image.clone());
+                }
+                if worker_txs[worker]
+                    .send((image, delta_base, duration))
+                    .is_err()
+                {
+                    return;
+                }
+                worker = (worker + 1) % workers;
+                frame += 1;
+            }
+            if frame == 0 {
+                return;
+            }
+        }
+    });
+    // the workers are read in the same order as they were given frames, to keep the frame order
+    let (frames, output) = sync_channel(buffer);
+    thread::spawn(move || {
+        for worker in (0..workers).cycle() {
+            match worker_rxs[worker].recv() {
+                Ok(Some(command)) => {
+                    if frames.send(command).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => return,
+            }
+        }
+    });
+    output
+}
+
+/// Show every frame as it arrives for its duration, or at `fps` if it has none
+pub fn manage_stream(frames: Receiver<TimedCommand>, fps: f32) -> impl FnMut(&mut Service) {
+    let mut schedule = Schedule::default();
+    move |service: &mut Service| {
+        let Ok(frame) = frames.recv() else {
+            sleep(IDLE_INTERVAL);
+            return;
+        };
+        // deltas of unchanged frames keep the previous frame painted
+        if !is_idle(&frame.command) {
+            service.send_command(frame.command);
+        }
+        schedule.wait(frame.duration.unwrap_or_else(|| frame_interval(fps)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        let (sender, output) = channel();
+        let mut timestamps = Timestamps {
+            output,
+            pending: HashMap::new(),
+        };
+        // frame 1 is missing from the log, frame 3 is logged after it is read
+        sender.send((0, 0.0)).unwrap();
+        sender.send((2, 0.08)).unwrap();
+        assert_eq!(timestamps.get(0), Some(0.0));
+        assert_eq!(timestamps.get(1), None);
+        assert_eq!(timestamps.get(2), Some(0.08));
+        let late = thread::spawn(move || {
+            sleep(Duration::from_millis(50));
+            sender.send((3, 0.12)).unwrap();
+        });
+        assert_eq!(timestamps.get(3), Some(0.12));
+        late.join().unwrap();
+        // the log is closed
+        assert_eq!(timestamps.get(4), None);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    fmt::Write as _,
+    io::{Error, ErrorKind, Result},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use crate::Transport;
+
+/// A TLS connection to a pixelflut server
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+/// The manager side of a TLS protected manager link
+pub type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub type Pin = [u8; 32];
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn tls_error(err: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Client config that trusts the Mozilla root certificates
+fn webpki_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+fn server_name(name: &str) -> Result<ServerName<'static>> {
+    // ipv6 addresses are written with brackets in urls
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(name.to_string()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+}
+
+/// Wrap `stream` in a TLS connection using `config`, and complete the handshake
+fn handshake<C, D>(mut conn: C, mut stream: TcpStream) -> Result<StreamOwned<C, TcpStream>>
+where
+    C: std::ops::DerefMut<Target = rustls::ConnectionCommon<D>>,
+    D: rustls::SideData,
+{
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(conn, stream))
+}
+
+/// Open a TLS connection to a pixelflut server, verified against the Mozilla root certificates
+pub fn connect(name: &str, stream: TcpStream) -> Result<TlsStream> {
+    let conn = ClientConnection::new(webpki_config(), server_name(name)?).map_err(tls_error)?;
+    handshake(conn, stream)
+}
+
+/// Open a TLS connection to a manager, only accepting the certificate matching `pin`
+pub fn connect_pinned(name: &str, stream: TcpStream, pin: Pin) -> Result<TlsStream> {
+    let provider = provider();
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { pin, provider }))
+        .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), server_name(name)?).map_err(tls_error)?;
+    handshake(conn, stream)
+}
+
+/// Accept a TLS connection from a manager client
+pub fn accept(config: Arc<ServerConfig>, stream: TcpStream) -> Result<TlsServerStream> {
+    let conn = ServerConnection::new(config).map_err(tls_error)?;
+    handshake(conn, stream)
+}
+
+fn load_chain(cert_path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    if chain.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "No certificate found"));
+    }
+    Ok(chain)
+}
+
+/// Server config for the manager from PEM encoded certificate chain and private key files
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let chain = load_chain(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// The pin of the leaf certificate in a PEM file, as used by `connect_pinned`
+pub fn certificate_pin(cert_path: &Path) -> Result<Pin> {
+    Ok(Sha256::digest(&load_chain(cert_path)?[0]).into())
+}
+
+/// Format a pin as lowercase hex
+pub fn pin_to_hex(pin: &Pin) -> String {
+    pin.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Parse a hex encoded pin, colons between the bytes are allowed
+pub fn parse_pin(hex: &str) -> std::result::Result<Pin, String> {
+    let hex = hex.replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("Expected a SHA-256 fingerprint of 64 hex digits".to_string());
+    }
+    let mut pin = [0; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|err| err.to_string())?;
+    }
+    Ok(pin)
+}
+
+/// Accepts exactly one certificate, regardless of who signed it and what names it is valid for
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: Pin,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Certificate does not match the pin".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl Transport for TlsStream {
+    fn shutdown(&self) -> Result<()> {
+        Transport::shutdown(&self.sock)
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        Transport::take_error(&self.sock)
+    }
+}
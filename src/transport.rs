@@ -0,0 +1,40 @@
+use std::{
+    io::{Error, Read, Result, Write},
+    net::{Shutdown, TcpStream},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// A bidirectional byte stream a pixelflut `Client` can talk over
+pub trait Transport: Read + Write {
+    /// Close the transport in both directions
+    fn shutdown(&self) -> Result<()>;
+
+    /// Get a pending error of the transport, if there is one
+    /// This is used to detect broken connections without writing to them
+    fn take_error(&self) -> Result<Option<Error>> {
+        Ok(None)
+    }
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&self) -> Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        TcpStream::take_error(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn shutdown(&self) -> Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        UnixStream::take_error(self)
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Result},
+    net::TcpStream,
+    os::fd::{AsFd, AsRawFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    libc,
+    poll::{poll, PollFd, PollFlags},
+    sys::socket::{recvmsg, send, ControlMessageOwned, MsgFlags},
+};
+
+use crate::{image_handler::Command, painter::CommandSink};
+
+/// How long to wait for the kernel to release buffers once it runs out of option memory
+const RECLAIM_TIMEOUT_MS: u16 = 100;
+/// How long dropping a client waits for the kernel to release all buffers
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// from linux/errqueue.h, not exposed by libc
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+/// A write only pixelflut client that sends commands without copying them into the kernel
+/// This uses `MSG_ZEROCOPY`, which requires Linux 4.14 or newer
+/// Commands are kept alive until the kernel reports that it is done with them, also when the
+/// client is dropped
+pub struct ZeroCopyClient {
+    stream: TcpStream,
+    /// Commands that are still referenced by the kernel, with the id of their latest send
+    in_flight: VecDeque<(u32, Arc<Command>)>,
+    /// Id of the next `send` call
+    next_id: u32,
+    /// All sends with ids below this one are completed
+    completed: u32,
+    reported_copy: bool,
+}
+
+impl ZeroCopyClient {
+    pub fn new(stream: TcpStream) -> Result<ZeroCopyClient> {
+        Self::try_new(stream).map_err(|(err, _)| err)
+    }
+
+    /// Like `new`, but hands the stream back if zero copy is not supported
+    pub(crate) fn try_new(
+        stream: TcpStream,
+    ) -> std::result::Result<ZeroCopyClient, (Error, TcpStream)> {
+        let enable: libc::c_int = 1;
+        // SAFETY: the option value points to a valid c_int for the whole call
+        let res = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ZEROCOPY,
+                &enable as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            return Err((Error::last_os_error(), stream));
+        }
+        Ok(ZeroCopyClient {
+            stream,
+            in_flight: VecDeque::new(),
+            next_id: 0,
+            completed: 0,
+            reported_copy: false,
+        })
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown(std::net::Shutdown::Both)
+    }
+
+    /// Read all completion notifications from the error queue without blocking
+    fn reap_completions(&mut self) -> Result<()> {
+        let mut cmsg_buffer = cmsg_space!(libc::sock_extended_err, libc::sockaddr_in6);
+        loop {
+            let res = recvmsg::<()>(
+                self.stream.as_raw_fd(),
+                &mut [],
+                Some(&mut cmsg_buffer),
+                MsgFlags::MSG_ERRQUEUE | MsgFlags::MSG_DONTWAIT,
+            );
+            let msg = match res {
+                Ok(msg) => msg,
+                Err(Errno::EAGAIN) => break,
+                Err(err) => return Err(err.into()),
+            };
+            for cmsg in msg.cmsgs()? {
+                let err = match cmsg {
+                    ControlMessageOwned::Ipv4RecvErr(err, _) => err,
+                    ControlMessageOwned::Ipv6RecvErr(err, _) => err,
+                    _ => continue,
+                };
+                if err.ee_origin != SO_EE_ORIGIN_ZEROCOPY {
+                    continue;
+                }
+                if err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0 && !self.reported_copy {
+                    info!("Kernel falls back to copying for zero copy sends");
+                    self.reported_copy = true;
+                }
+                // ee_data is the (inclusive) upper end of the completed range
+                self.completed = err.ee_data.wrapping_add(1);
+            }
+        }
+        while let Some((id, _)) = self.in_flight.front() {
+            // wrapping comparison of id < completed
+            if self.completed.wrapping_sub(*id).wrapping_sub(1) < u32::MAX / 2 {
+                self.in_flight.pop_front();
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_for_completions(&mut self) -> Result<()> {
+        // POLLERR is always reported, no need to request it
+        let mut fds = [PollFd::new(self.stream.as_fd(), PollFlags::empty())];
+        poll(&mut fds, RECLAIM_TIMEOUT_MS)?;
+        self.reap_completions()
+    }
+
+    /// Wait until the kernel released all buffers, or `timeout` passed
+    fn drain(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.reap_completions()?;
+        while !self.in_flight.is_empty() && Instant::now() < deadline {
+            self.wait_for_completions()?;
+        }
+        Ok(())
+    }
+
+    fn track(&mut self, commands: &Arc<Command>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        match self.in_flight.back_mut() {
+            Some((last_id, last)) if Arc::ptr_eq(last, commands) => *last_id = id,
+            _ => self.in_flight.push_back((id, commands.clone())),
+        }
+    }
+}
+
+impl CommandSink for ZeroCopyClient {
+    fn send_chunk(&mut self, commands: &Arc<Command>, chunk: usize) -> Result<()> {
+        let data = &commands[chunk];
+        let flags = MsgFlags::from_bits_retain(libc::MSG_ZEROCOPY);
+        let mut written = 0;
+        while written < data.len() {
+            match send(self.stream.as_raw_fd(), &data[written..], flags) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    written += n;
+                    self.track(commands);
+                }
+                // the kernel ran out of memory to pin further pages
+                Err(Errno::ENOBUFS) => self.wait_for_completions()?,
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.reap_completions()
+    }
+}
+
+impl Drop for ZeroCopyClient {
+    fn drop(&mut self) {
+        if let Err(err) = self.drain(DRAIN_TIMEOUT) {
+            warn!("Unable to wait for zero copy sends to complete ({err})");
+        }
+        if !self.in_flight.is_empty() {
+            warn!("Zero copy sends did not complete, leaking their buffers");
+            // the kernel may still read them, so they must never be freed
+            for (_, commands) in self.in_flight.drain(..) {
+                std::mem::forget(commands);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_drop_with_sends_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+        let Ok(mut client) = ZeroCopyClient::new(TcpStream::connect(addr).unwrap()) else {
+            // zero copy is not supported here
+            return;
+        };
+        let commands: Arc<Command> = Arc::new((0..64u8).map(|i| vec![i; 64 * 1024]).collect());
+        for chunk in 0..commands.len() {
+            client.send_chunk(&commands, chunk).unwrap();
+        }
+        let start = Instant::now();
+        drop(client);
+        assert!(start.elapsed() < DRAIN_TIMEOUT + Duration::from_secs(1));
+        // the client waited for the kernel instead of leaking the commands
+        assert_eq!(Arc::strong_count(&commands), 1);
+        assert_eq!(receiver.join().unwrap(), commands.concat());
+    }
+}